@@ -1,14 +1,31 @@
+use crate::config::{AttributionRankingConfig, RankingsConfig};
+use crate::logging;
 use crate::models::{
-    AggregatedPlayerAttribution, AggregationTimeRange, AttributionRecordProvider, BreakCount,
-    BuildCount, PlayTicks, Ranking, VoteCount,
+    AggregatedPlayerAttribution, AggregationTimeRange, AttributionKind, AttributionRecordProvider,
+    AttributionUnit, BreakCount, BuildCount, PlayTicks, Player, Ranking, VoteCount,
 };
-use anyhow::{Ok, Result};
-use async_lock::RwLock;
-use log::error;
+use crate::snapshot_store::RankingSnapshotStore;
+use anyhow::{Context, Ok, Result};
+use async_lock::{Mutex, RwLock};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
 use std::ops::Deref;
-use std::thread::sleep;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 use strum::IntoEnumIterator;
+use tokio::sync::{broadcast, watch};
+use uuid::Uuid;
 
 pub struct LockedRankingsForTimeRanges<Attribution: AggregatedPlayerAttribution> {
     all: RwLock<Ranking<Attribution>>,
@@ -48,12 +65,307 @@ impl<Attribution: AggregatedPlayerAttribution> LockedRankingsForTimeRanges<Attri
     }
 }
 
+/// [`LockedRankingsForTimeRanges`] の中身をロックの外に取り出したもの。状態ファイルへの読み書きに使う。
+#[derive(Serialize, Deserialize)]
+struct RankingsForTimeRanges<Attribution: AggregatedPlayerAttribution> {
+    all: Ranking<Attribution>,
+    last_one_year: Ranking<Attribution>,
+    last_one_month: Ranking<Attribution>,
+    last_one_week: Ranking<Attribution>,
+    last_one_day: Ranking<Attribution>,
+}
+
+impl<Attribution: AggregatedPlayerAttribution> LockedRankingsForTimeRanges<Attribution> {
+    async fn read_all(&self) -> RankingsForTimeRanges<Attribution> {
+        RankingsForTimeRanges {
+            all: self.all.read().await.clone(),
+            last_one_year: self.last_one_year.read().await.clone(),
+            last_one_month: self.last_one_month.read().await.clone(),
+            last_one_week: self.last_one_week.read().await.clone(),
+            last_one_day: self.last_one_day.read().await.clone(),
+        }
+    }
+
+    async fn replace_all(&self, rankings: RankingsForTimeRanges<Attribution>) {
+        *self.all.write().await = rankings.all;
+        *self.last_one_year.write().await = rankings.last_one_year;
+        *self.last_one_month.write().await = rankings.last_one_month;
+        *self.last_one_week.write().await = rankings.last_one_week;
+        *self.last_one_day.write().await = rankings.last_one_day;
+    }
+}
+
+/// ランキングが更新されたことの通知
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RankingUpdate {
+    pub kind: AttributionKind,
+    pub time_range: AggregationTimeRange,
+}
+
+/// あるプレーヤーの、一つの種類のランキングでの順位と値
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlayerSummaryEntry {
+    pub kind: AttributionKind,
+    pub unit: AttributionUnit,
+    pub rank: u32,
+    pub value: u64,
+}
+
+/// あるプレーヤーの、一つの集計期間でのすべての種類のランキングでの順位と値
+pub struct PlayerSummary {
+    pub player: Player,
+    /// プレーヤーが含まれているランキングのものだけが、 [`AttributionKind`] の宣言順に並ぶ
+    pub entries: Vec<PlayerSummaryEntry>,
+}
+
+impl PlayerSummary {
+    pub fn entry(&self, kind: AttributionKind) -> Option<&PlayerSummaryEntry> {
+        self.entries.iter().find(|entry| entry.kind == kind)
+    }
+}
+
+async fn player_summary_entry<Attribution: AggregatedPlayerAttribution>(
+    rankings: &LockedRankingsForTimeRanges<Attribution>,
+    uuid: Uuid,
+    time_range: AggregationTimeRange,
+) -> Option<(Player, PlayerSummaryEntry)> {
+    let record = rankings
+        .for_time_range(time_range)
+        .read()
+        .await
+        .record_with_uuid(uuid)?;
+
+    Some((
+        record.attribution_record.player,
+        PlayerSummaryEntry {
+            kind: Attribution::KIND,
+            unit: Attribution::UNIT,
+            rank: record.rank,
+            value: record.attribution_record.attribution.raw_u64_data(),
+        },
+    ))
+}
+
 #[derive(Default)]
+struct PrivacyListContent {
+    players: HashSet<Uuid>,
+    generation: u64,
+}
+
+/// 名前を公開しないことを選んだプレーヤーの一覧。
+///
+/// 一覧にいるプレーヤーも順位には数えられるが、レスポンスでは名前が匿名のラベルに、
+/// UUID が [`PrivacyList::pseudonym`] に置き換えられ、最終ログアウトは隠される。
+/// 置き換えは [`crate::handlers::presentation_models`] の変換で行われる。
+pub struct PrivacyList {
+    content: std::sync::RwLock<PrivacyListContent>,
+    /// 起動するたびに作り直される、仮の UUID を作るための鍵
+    pseudonym_key: [u8; 16],
+    /// 一覧が変わるたびに世代番号を送るチャンネル
+    changes: watch::Sender<u64>,
+}
+
+impl Default for PrivacyList {
+    fn default() -> Self {
+        Self {
+            content: std::sync::RwLock::default(),
+            pseudonym_key: Uuid::new_v4().into_bytes(),
+            changes: watch::channel(0).0,
+        }
+    }
+}
+
+impl PrivacyList {
+    pub fn contains(&self, uuid: Uuid) -> bool {
+        self.content.read().unwrap().players.contains(&uuid)
+    }
+
+    /// `uuid` の代わりにレスポンスに含める仮の UUID。
+    ///
+    /// 同じプロセスの中では同じプレーヤーに同じ値を返すが、鍵を知らなければ元の UUID は分からない。
+    pub fn pseudonym(&self, uuid: Uuid) -> Uuid {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.pseudonym_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(uuid.as_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+
+    /// UUID の順に並べた一覧
+    pub fn players(&self) -> Vec<Uuid> {
+        let mut players = Vec::from_iter(self.content.read().unwrap().players.iter().copied());
+        players.sort_unstable();
+        players
+    }
+
+    /// 一覧が変わるたびに増える世代番号。一覧から作ったものを使い回してよいかの判断に使う。
+    pub fn generation(&self) -> u64 {
+        self.content.read().unwrap().generation
+    }
+
+    /// 一覧が変わるたびに通知を受け取る
+    pub fn subscribe_changes(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// `uuid` を一覧に加える。既に一覧にいた場合は `false` を返す。
+    pub fn insert(&self, uuid: Uuid) -> bool {
+        let mut content = self.content.write().unwrap();
+        let inserted = content.players.insert(uuid);
+        if inserted {
+            content.generation += 1;
+            self.changes.send_replace(content.generation);
+        }
+        inserted
+    }
+
+    /// `uuid` を一覧から取り除く。一覧にいなかった場合は `false` を返す。
+    pub fn remove(&self, uuid: Uuid) -> bool {
+        let mut content = self.content.write().unwrap();
+        let removed = content.players.remove(&uuid);
+        if removed {
+            content.generation += 1;
+            self.changes.send_replace(content.generation);
+        }
+        removed
+    }
+
+    pub fn extend(&self, uuids: impl IntoIterator<Item = Uuid>) {
+        for uuid in uuids {
+            self.insert(uuid);
+        }
+    }
+}
+
 pub struct AppState {
     pub break_count_rankings: LockedRankingsForTimeRanges<BreakCount>,
     pub build_count_rankings: LockedRankingsForTimeRanges<BuildCount>,
     pub play_ticks_rankings: LockedRankingsForTimeRanges<PlayTicks>,
     pub vote_count_rankings: LockedRankingsForTimeRanges<VoteCount>,
+    pub privacy_list: PrivacyList,
+    /// 定期的な更新と手動の更新が同時に走らないよう、ランキングの更新中に取るロック
+    rehydration_lock: Mutex<()>,
+    /// ランキングが更新されるたびに通知を送るチャンネル
+    ranking_updates: broadcast::Sender<RankingUpdate>,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self {
+            break_count_rankings: LockedRankingsForTimeRanges::default(),
+            build_count_rankings: LockedRankingsForTimeRanges::default(),
+            play_ticks_rankings: LockedRankingsForTimeRanges::default(),
+            vote_count_rankings: LockedRankingsForTimeRanges::default(),
+            privacy_list: PrivacyList::default(),
+            rehydration_lock: Mutex::default(),
+            ranking_updates: broadcast::channel(Self::RANKING_UPDATES_CAPACITY).0,
+        }
+    }
+}
+
+/// 状態ファイルの中身
+#[derive(Serialize, Deserialize)]
+struct AppStateFileContent {
+    saved_at: DateTime<Utc>,
+    break_count_rankings: RankingsForTimeRanges<BreakCount>,
+    build_count_rankings: RankingsForTimeRanges<BuildCount>,
+    play_ticks_rankings: RankingsForTimeRanges<PlayTicks>,
+    vote_count_rankings: RankingsForTimeRanges<VoteCount>,
+}
+
+impl AppState {
+    /// 受け取られていない通知をこの数まで溜めておく。これを超えると古い通知から捨てられる。
+    const RANKING_UPDATES_CAPACITY: usize = 64;
+
+    /// `uuid` のプレーヤーの、 `time_range` のすべての種類のランキングでの順位と値。
+    /// どのランキングにも含まれていない場合は `None` を返す。
+    pub async fn player_summary(
+        &self,
+        uuid: Uuid,
+        time_range: AggregationTimeRange,
+    ) -> Option<PlayerSummary> {
+        let found = [
+            player_summary_entry(&self.break_count_rankings, uuid, time_range).await,
+            player_summary_entry(&self.build_count_rankings, uuid, time_range).await,
+            player_summary_entry(&self.play_ticks_rankings, uuid, time_range).await,
+            player_summary_entry(&self.vote_count_rankings, uuid, time_range).await,
+        ];
+
+        let mut player = None;
+        let mut entries = vec![];
+        for (found_player, entry) in found.into_iter().flatten() {
+            player.get_or_insert(found_player);
+            entries.push(entry);
+        }
+
+        Some(PlayerSummary {
+            player: player?,
+            entries,
+        })
+    }
+
+    /// これ以降にランキングが更新されるたびに通知を受け取る。
+    pub fn subscribe_ranking_updates(&self) -> broadcast::Receiver<RankingUpdate> {
+        self.ranking_updates.subscribe()
+    }
+
+    /// すべてのランキングを gzip 圧縮した JSON として `path` に書き出す。
+    ///
+    /// 書き込み途中でプロセスが終了しても既存のファイルが壊れないよう、一時ファイルに書き込んでから置き換える。
+    pub async fn save_to_file(&self, path: &Path) -> Result<()> {
+        let content = AppStateFileContent {
+            saved_at: Utc::now(),
+            break_count_rankings: self.break_count_rankings.read_all().await,
+            build_count_rankings: self.build_count_rankings.read_all().await,
+            play_ticks_rankings: self.play_ticks_rankings.read_all().await,
+            vote_count_rankings: self.vote_count_rankings.read_all().await,
+        };
+
+        // 圧縮とファイルの書き込みはブロックするので、ランタイムのスレッドの外で行う
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let temporary_path = path.with_extension("tmp");
+            let mut encoder = GzEncoder::new(
+                BufWriter::new(File::create(&temporary_path)?),
+                Compression::default(),
+            );
+            serde_json::to_writer(&mut encoder, &content)?;
+            encoder.finish()?.flush()?;
+            fs::rename(&temporary_path, &path)?;
+
+            Ok(())
+        })
+        .await?
+    }
+
+    /// [`AppState::save_to_file`] で書き出したファイルからすべてのランキングを復元し、ファイルが書き出された時刻を返す。
+    pub async fn restore_from_file(&self, path: &Path) -> Result<DateTime<Utc>> {
+        let path = path.to_path_buf();
+        let content: AppStateFileContent = tokio::task::spawn_blocking(move || {
+            Ok(serde_json::from_reader(GzDecoder::new(BufReader::new(
+                File::open(path)?,
+            )))?)
+        })
+        .await??;
+
+        self.break_count_rankings
+            .replace_all(content.break_count_rankings)
+            .await;
+        self.build_count_rankings
+            .replace_all(content.build_count_rankings)
+            .await;
+        self.play_ticks_rankings
+            .replace_all(content.play_ticks_rankings)
+            .await;
+        self.vote_count_rankings
+            .replace_all(content.vote_count_rankings)
+            .await;
+
+        Ok(content.saved_at)
+    }
 }
 
 pub struct AllAttributionRecordProviders {
@@ -63,56 +375,277 @@ pub struct AllAttributionRecordProviders {
     pub vote_count_provider: Box<dyn AttributionRecordProvider<VoteCount> + Sync + Send>,
 }
 
-async fn rehydrate_attribution<Attribution: AggregatedPlayerAttribution>(
+/// 最初に使われるときに作られる [`AllAttributionRecordProviders`]
+pub type LazyAttributionRecordProviders = LazyLock<AllAttributionRecordProviders>;
+
+async fn rehydrate_attribution<Attribution: AggregatedPlayerAttribution + Send + 'static>(
     locked_rankings: &LockedRankingsForTimeRanges<Attribution>,
     provider: &(dyn AttributionRecordProvider<Attribution> + Sync + Send),
+    config: &AttributionRankingConfig,
+    snapshot_store: Option<&Arc<RankingSnapshotStore>>,
+    ranking_updates: &broadcast::Sender<RankingUpdate>,
+    time_range: Option<AggregationTimeRange>,
 ) -> Result<()> {
-    for time_range in AggregationTimeRange::iter() {
-        let records = provider.get_all_attribution_records(time_range).await?;
-        let mut ranking = locked_rankings.for_time_range(time_range).write().await;
-        ranking.hydrate_record_set(records);
+    let time_ranges = match time_range {
+        Some(time_range) => vec![time_range],
+        None => AggregationTimeRange::iter().collect(),
+    };
+
+    for time_range in time_ranges {
+        let mut records = provider.get_all_attribution_records(time_range).await?;
+        records.retain(|record| !config.excluded_players.contains(&record.player.uuid));
+
+        let snapshot = {
+            let mut ranking = locked_rankings.for_time_range(time_range).write().await;
+            ranking.hydrate_record_set(records);
+            // SQLite への書き込みの間ランキングをロックしたままにしないよう、複製してから書き込む
+            snapshot_store.map(|_| ranking.clone())
+        };
+
+        if let (Some(store), Some(snapshot)) = (snapshot_store, snapshot) {
+            let store = Arc::clone(store);
+            let taken_at = Utc::now();
+            let persisted =
+                tokio::task::spawn_blocking(move || store.persist(time_range, taken_at, &snapshot))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|result| result);
+            if let Err(e) = persisted {
+                error!(
+                    "Error persisting ranking snapshot (kind={}, time-range={time_range}): {e}",
+                    Attribution::KIND
+                );
+            }
+        }
+
+        // 受け取る側がいなければ送れないが、それで構わない
+        let _ = ranking_updates.send(RankingUpdate {
+            kind: Attribution::KIND,
+            time_range,
+        });
     }
 
     Ok(())
 }
 
-async fn rehydrate_once(
+/// `kind` のランキングを、`time_range` の集計期間 (`None` の場合はすべての集計期間) について一度だけ更新する。
+///
+/// ほかの更新が進行中であれば、それが終わるのを待ってから更新する。
+pub async fn rehydrate_kind(
+    kind: AttributionKind,
+    time_range: Option<AggregationTimeRange>,
     state_ref: &AppState,
     providers: &AllAttributionRecordProviders,
+    rankings_config: &RankingsConfig,
+    snapshot_store: Option<&Arc<RankingSnapshotStore>>,
 ) -> Result<()> {
-    rehydrate_attribution(
-        &state_ref.break_count_rankings,
-        providers.break_count_provider.deref(),
-    )
-    .await?;
-
-    rehydrate_attribution(
-        &state_ref.build_count_rankings,
-        providers.build_count_provider.deref(),
-    )
-    .await?;
-
-    rehydrate_attribution(
-        &state_ref.play_ticks_rankings,
-        providers.play_ticks_provider.deref(),
-    )
-    .await?;
-
-    rehydrate_attribution(
-        &state_ref.vote_count_rankings,
-        providers.vote_count_provider.deref(),
-    )
-    .await?;
+    let _rehydrating = state_ref.rehydration_lock.lock().await;
 
-    Ok(())
+    match kind {
+        AttributionKind::Break => {
+            rehydrate_attribution(
+                &state_ref.break_count_rankings,
+                providers.break_count_provider.deref(),
+                &rankings_config.break_count,
+                snapshot_store,
+                &state_ref.ranking_updates,
+                time_range,
+            )
+            .await
+        }
+        AttributionKind::Build => {
+            rehydrate_attribution(
+                &state_ref.build_count_rankings,
+                providers.build_count_provider.deref(),
+                &rankings_config.build_count,
+                snapshot_store,
+                &state_ref.ranking_updates,
+                time_range,
+            )
+            .await
+        }
+        AttributionKind::PlayTicks => {
+            rehydrate_attribution(
+                &state_ref.play_ticks_rankings,
+                providers.play_ticks_provider.deref(),
+                &rankings_config.play_ticks,
+                snapshot_store,
+                &state_ref.ranking_updates,
+                time_range,
+            )
+            .await
+        }
+        AttributionKind::VoteCount => {
+            rehydrate_attribution(
+                &state_ref.vote_count_rankings,
+                providers.vote_count_provider.deref(),
+                &rankings_config.vote_count,
+                snapshot_store,
+                &state_ref.ranking_updates,
+                time_range,
+            )
+            .await
+        }
+    }
+}
+
+/// `shutdown` に `true` が送られるか、送信側が破棄されるまで待つ。
+pub(crate) async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|requested| *requested).await;
 }
 
-pub async fn rehydration_process(state_ref: &AppState, providers: AllAttributionRecordProviders) {
-    const SLEEP_SECS: u64 = 120;
+/// ランキングの更新に使うもの。定期的な更新と、管理用 API から求められた更新とで共有する。
+pub struct Rehydrator {
+    pub state: &'static AppState,
+    pub providers: &'static LazyAttributionRecordProviders,
+    pub rankings_config: Arc<RankingsConfig>,
+    pub snapshot_store: Option<Arc<RankingSnapshotStore>>,
+    pub state_file: Option<PathBuf>,
+}
+
+impl Rehydrator {
+    /// [`rehydrate_kind`] を参照。
+    pub async fn rehydrate_kind(
+        &self,
+        kind: AttributionKind,
+        time_range: Option<AggregationTimeRange>,
+    ) -> Result<()> {
+        rehydrate_kind(
+            kind,
+            time_range,
+            self.state,
+            self.providers,
+            &self.rankings_config,
+            self.snapshot_store.as_ref(),
+        )
+        .await
+    }
+
+    /// ランキングを更新した後に、状態ファイルが設定されていれば書き出す。
+    pub async fn save_state(&self) -> Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+
+        self.state
+            .save_to_file(path)
+            .await
+            .with_context(|| format!("failed to save state file to {}", path.display()))
+    }
+}
+
+/// 種類ごとに設定された間隔で、`shutdown` に `true` が送られるまでランキングを更新し続ける。
+///
+/// 終了を求められた後に状態ファイルの書き出しに失敗した場合は、エラーを返す。
+pub async fn rehydration_process(
+    rehydrator: &Rehydrator,
+    mut shutdown: watch::Receiver<bool>,
+    shutdown_timeout: Duration,
+) -> Result<()> {
+    let rankings_config = &rehydrator.rankings_config;
+    let tick = AttributionKind::iter()
+        .map(|kind| rankings_config.for_kind(kind).refresh_interval())
+        .min()
+        .unwrap_or_default();
+    let mut last_rehydrated_at = HashMap::<AttributionKind, Instant>::new();
+
     loop {
-        if let Err(e) = rehydrate_once(state_ref, &providers).await {
-            error!("Error rehydrating ranking cache: {e}")
+        let mut any_rehydrated = false;
+
+        for kind in AttributionKind::iter() {
+            if *shutdown.borrow() {
+                break;
+            }
+
+            let is_due = last_rehydrated_at
+                .get(&kind)
+                .is_none_or(|at| at.elapsed() >= rankings_config.for_kind(kind).refresh_interval());
+            if !is_due {
+                continue;
+            }
+
+            let rehydration = logging::with_request_id(logging::new_request_id(), async {
+                rehydrator
+                    .rehydrate_kind(kind, None)
+                    .await
+                    .map_err(|e| error!("Error rehydrating ranking cache (kind={kind}): {e}"))
+                    .is_ok()
+            });
+            tokio::pin!(rehydration);
+
+            let rehydrated = tokio::select! {
+                rehydrated = &mut rehydration => rehydrated,
+                () = shutdown_requested(&mut shutdown) => {
+                    info!("Waiting for the running rehydration (kind={kind}) to finish");
+                    tokio::time::timeout(shutdown_timeout, rehydration)
+                        .await
+                        .unwrap_or_else(|_| {
+                            warn!("Cancelled the running rehydration (kind={kind})");
+                            false
+                        })
+                }
+            };
+
+            if rehydrated {
+                last_rehydrated_at.insert(kind, Instant::now());
+                any_rehydrated = true;
+            }
+        }
+
+        let shutting_down = *shutdown.borrow();
+
+        if any_rehydrated {
+            if let Err(e) = rehydrator.save_state().await {
+                if shutting_down {
+                    return Err(e);
+                }
+                error!("Error saving state: {e:#}");
+            }
+        }
+
+        if shutting_down {
+            return Ok(());
+        }
+
+        tokio::select! {
+            () = tokio::time::sleep(tick) => {}
+            () = shutdown_requested(&mut shutdown) => {}
         }
-        sleep(std::time::Duration::from_secs(SLEEP_SECS))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::AppState;
+    use crate::models::{AggregatedPlayerAttribution, AggregationTimeRange};
+    use crate::test_fixtures::record;
+    use uuid::Uuid;
+
+    #[actix_web::test]
+    async fn restore_rankings_saved_to_file() {
+        let path = std::env::temp_dir().join(format!("app-state-{}.json.gz", std::process::id()));
+
+        let state = AppState::default();
+        state
+            .break_count_rankings
+            .for_time_range(AggregationTimeRange::LastOneWeek)
+            .write()
+            .await
+            .hydrate_record_set(vec![record(1, 42)]);
+        state.save_to_file(&path).await.unwrap();
+
+        let restored = AppState::default();
+        restored.restore_from_file(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let record = restored
+            .break_count_rankings
+            .for_time_range(AggregationTimeRange::LastOneWeek)
+            .read()
+            .await
+            .record_with_uuid(Uuid::from_u128(1))
+            .unwrap();
+        assert_eq!(record.rank, 1);
+        assert_eq!(record.attribution_record.attribution.raw_u64_data(), 42);
     }
 }
@@ -0,0 +1,194 @@
+use crate::config::{ApiKey, ApiKeyScope};
+use actix_web::body::{BoxBody, EitherBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// API キーを入れるヘッダー。 `Authorization: Bearer` でも渡せる。
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// リクエストに付けられていた正しい API キー。 [`ApiKeyAuthentication`] がリクエストの extensions に入れる。
+#[derive(Clone, Debug)]
+pub struct AuthenticatedApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+/// API キーごとの、受け付けたリクエストの数
+#[derive(Default)]
+pub struct ApiKeyUsage {
+    requests: BTreeMap<String, AtomicU64>,
+}
+
+impl ApiKeyUsage {
+    pub fn new(keys: &[ApiKey]) -> Self {
+        Self {
+            requests: keys
+                .iter()
+                .map(|key| (key.name.clone(), AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    fn record(&self, name: &str) {
+        if let Some(count) = self.requests.get(name) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Prometheus のテキスト形式で書き出す。
+    pub fn to_prometheus_text(&self) -> String {
+        let mut text = String::from(
+            "# HELP api_key_requests_total Requests authenticated with each API key.\n\
+             # TYPE api_key_requests_total counter\n",
+        );
+        for (name, count) in &self.requests {
+            let _ = writeln!(
+                text,
+                "api_key_requests_total{{key=\"{name}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+        text
+    }
+}
+
+/// 長さが同じなら、内容によらず同じ時間で比較する
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// リクエストに付けられた API キー。正しいかどうかは問わない。
+pub(crate) fn presented_api_key(request: &ServiceRequest) -> Option<&str> {
+    let headers = request.headers();
+    headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        })
+}
+
+/// `presented` と一致する API キーを `keys` から探す。
+pub(crate) fn find_api_key<'a>(keys: &'a [ApiKey], presented: &str) -> Option<&'a ApiKey> {
+    keys.iter()
+        .find(|key| constant_time_eq(presented.as_bytes(), key.key.as_bytes()))
+}
+
+/// `X-Api-Key` ヘッダーか `Authorization: Bearer` ヘッダーで渡された API キーを検証するミドルウェア。
+///
+/// キーが渡されなかったリクエストは匿名のリクエストとしてそのまま通す。
+/// 正しくないキーが渡されたリクエストには 401 を返す。
+pub struct ApiKeyAuthentication {
+    keys: Arc<Vec<ApiKey>>,
+    usage: Arc<ApiKeyUsage>,
+}
+
+impl ApiKeyAuthentication {
+    pub const fn new(keys: Arc<Vec<ApiKey>>, usage: Arc<ApiKeyUsage>) -> Self {
+        Self { keys, usage }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ApiKeyAuthentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = actix_web::Error;
+    type Transform = ApiKeyAuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiKeyAuthenticationMiddleware {
+            service,
+            keys: self.keys.clone(),
+            usage: self.usage.clone(),
+        }))
+    }
+}
+
+pub struct ApiKeyAuthenticationMiddleware<S> {
+    service: S,
+    keys: Arc<Vec<ApiKey>>,
+    usage: Arc<ApiKeyUsage>,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiKeyAuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let Some(presented) = presented_api_key(&request) else {
+            let response = self.service.call(request);
+            return Box::pin(async move { Ok(response.await?.map_into_left_body()) });
+        };
+
+        let authenticated = find_api_key(&self.keys, presented).map(|key| AuthenticatedApiKey {
+            name: key.name.clone(),
+            scopes: key.scopes.clone(),
+        });
+
+        let Some(authenticated) = authenticated else {
+            warn!(
+                "Rejected an invalid API key for {} {}",
+                request.method(),
+                request.path()
+            );
+            let response = HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .body("the API key is not valid");
+            return Box::pin(ready(Ok(request
+                .into_response(response)
+                .map_into_right_body())));
+        };
+
+        info!(
+            "API key {} used for {} {}",
+            authenticated.name,
+            request.method(),
+            request.path()
+        );
+        self.usage.record(&authenticated.name);
+        request.extensions_mut().insert(authenticated);
+
+        let response = self.service.call(request);
+        Box::pin(async move { Ok(response.await?.map_into_left_body()) })
+    }
+}
+
+/// `scope` の権限を持つ API キーが付いていないリクエストであれば、返すべきレスポンスを返す。
+pub(crate) fn require_scope(req: &HttpRequest, scope: ApiKeyScope) -> Option<HttpResponse> {
+    match req.extensions().get::<AuthenticatedApiKey>() {
+        None => Some(
+            HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .body(format!("an API key with the {scope} scope is required")),
+        ),
+        Some(key) if !key.scopes.contains(&scope) => Some(HttpResponse::Forbidden().body(format!(
+            "the API key {} does not have the {scope} scope",
+            key.name
+        ))),
+        Some(_) => None,
+    }
+}
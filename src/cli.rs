@@ -0,0 +1,155 @@
+use crate::app_models::{AppState, PrivacyList};
+use crate::config::DatabaseAuthorizationInfo;
+use crate::handlers::presentation_models::{
+    displayed_player_name, ranked_record_to_presentation_player_ranking_record,
+};
+use crate::models::{
+    AggregatedPlayerAttribution, AggregationTimeRange, AttributionKind, RankingSlice,
+};
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// 設定ファイル (TOML) のパス
+    #[arg(long, env = "CONFIG_FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// 指定されていない場合は `serve` として扱う
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// HTTP サーバーを起動し、ランキングを定期的に更新する
+    Serve,
+    /// 設定を検証し、データベースに接続できるかを確かめる
+    CheckConfig,
+    /// ランキングを一度だけ取得して出力する
+    DumpRanking(RankingSelection),
+    /// 状態ファイルからランキングを読み込んで出力する
+    LoadSnapshot {
+        /// 状態ファイルのパス
+        path: PathBuf,
+        #[command(flatten)]
+        selection: RankingSelection,
+    },
+}
+
+#[derive(Args)]
+pub struct RankingSelection {
+    /// ランキングの種類 (`break`, `build`, `play_ticks`, `vote_count`)
+    #[arg(long = "type", default_value = "break")]
+    pub kind: AttributionKind,
+    /// 集計期間 (`all`, `year`, `month`, `week`, `day`)
+    #[arg(long, default_value = "all")]
+    pub time_range: AggregationTimeRange,
+    #[arg(long, default_value_t = 0)]
+    pub offset: usize,
+    #[arg(long, default_value_t = 20)]
+    pub limit: usize,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    /// `/ranking` のレスポンスと同じ形式
+    Json,
+}
+
+/// データベースのホストに TCP で接続できるかを確かめる。認証情報が正しいかどうかは確かめない。
+pub fn check_database_reachable(database: &DatabaseAuthorizationInfo) -> Result<()> {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    let addresses = (database.host.as_str(), database.port.0)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {}", database.host))?;
+
+    let mut last_error = None;
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e)
+            .with_context(|| format!("failed to connect to {}:{}", database.host, database.port.0)),
+        None => anyhow::bail!("{} did not resolve to any address", database.host),
+    }
+}
+
+fn write_ranking_slice<Attribution: AggregatedPlayerAttribution>(
+    slice: &RankingSlice<Attribution>,
+    privacy_list: &PrivacyList,
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> Result<()> {
+    match format {
+        OutputFormat::Table => {
+            writeln!(
+                out,
+                "{:>6}  {:<36}  {:<16}  {:>20}",
+                "rank", "uuid", "name", "value"
+            )?;
+            for record in &slice.0 {
+                let attribution_record = &record.attribution_record;
+                writeln!(
+                    out,
+                    "{:>6}  {:<36}  {:<16}  {:>20}",
+                    record.rank,
+                    attribution_record.player.uuid,
+                    displayed_player_name(&attribution_record.player, privacy_list),
+                    attribution_record.attribution.raw_u64_data()
+                )?;
+            }
+        }
+        OutputFormat::Json => {
+            let records = slice
+                .0
+                .iter()
+                .map(|record| {
+                    ranked_record_to_presentation_player_ranking_record(record, privacy_list)
+                })
+                .collect::<Vec<_>>();
+            serde_json::to_writer_pretty(&mut *out, &records)?;
+            writeln!(out)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `state` から `selection` で選ばれたランキングを `out` に書き出す。
+pub async fn write_ranking(
+    state: &AppState,
+    selection: &RankingSelection,
+    out: &mut impl Write,
+) -> Result<()> {
+    macro_rules! write_using {
+        ($ranking:expr) => {{
+            let slice = $ranking
+                .for_time_range(selection.time_range)
+                .read()
+                .await
+                .paginate(selection.offset, selection.limit);
+            write_ranking_slice(&slice, &state.privacy_list, selection.format, out)
+        }};
+    }
+
+    match selection.kind {
+        AttributionKind::Break => write_using!(state.break_count_rankings),
+        AttributionKind::Build => write_using!(state.build_count_rankings),
+        AttributionKind::PlayTicks => write_using!(state.play_ticks_rankings),
+        AttributionKind::VoteCount => write_using!(state.vote_count_rankings),
+    }
+}
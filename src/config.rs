@@ -1,6 +1,15 @@
-use anyhow::Result;
+use crate::models::AttributionKind;
+use anyhow::{bail, Context, Result};
 use envy::Error;
+use log::LevelFilter;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+use strum::{Display, EnumString};
+use uuid::Uuid;
 
 pub trait FromEnv: Sized {
     fn from_env() -> Result<Self, Error>;
@@ -8,6 +17,24 @@ pub trait FromEnv: Sized {
 
 trait FromEnvLikeKeyValuePairs: Sized {
     fn from_iter(iter: impl Iterator<Item = (String, String)> + Clone) -> Result<Self, Error>;
+
+    /// 読み込む可能性のある、環境変数と同じ形式のキー。設定ファイルに知らないキーが書かれていないかを調べるのに使う。
+    fn keys() -> Vec<String>;
+}
+
+/// 環境変数のような組のうち、 `PREFIX` から始まるものだけから読み込む設定
+trait PrefixedConfig: DeserializeOwned {
+    const PREFIX: &'static str;
+}
+
+impl<T: PrefixedConfig> FromEnvLikeKeyValuePairs for T {
+    fn from_iter(iter: impl Iterator<Item = (String, String)> + Clone) -> Result<Self, Error> {
+        from_prefixed_iter(T::PREFIX, iter)
+    }
+
+    fn keys() -> Vec<String> {
+        prefixed_keys::<T>(T::PREFIX)
+    }
 }
 
 impl<T: FromEnvLikeKeyValuePairs> FromEnv for T {
@@ -17,20 +44,251 @@ impl<T: FromEnvLikeKeyValuePairs> FromEnv for T {
     }
 }
 
+/// `prefix` から始まる環境変数のような組から `T` を読み込む。
+///
+/// envy のエラーメッセージには `prefix` が含まれず、値が不正な場合はどの項目の値なのかも含まれないことがあるので、
+/// どの設定項目が問題なのかわかるように補う。
+fn from_prefixed_iter<T: DeserializeOwned>(
+    prefix: &'static str,
+    iter: impl Iterator<Item = (String, String)>,
+) -> Result<T, Error> {
+    let pairs = iter
+        .filter(|(key, _)| key.starts_with(prefix))
+        .collect::<Vec<_>>();
+
+    envy::prefixed(prefix)
+        .from_iter(pairs.clone())
+        .map_err(|e| match e {
+            Error::MissingValue(field) => Error::Custom(format!(
+                "missing value for {prefix}{}",
+                field.to_uppercase()
+            )),
+            Error::Custom(message) => {
+                // 取り除くと同じエラーが起きなくなる項目が、不正な値の項目
+                let invalid_key = pairs.iter().map(|(key, _)| key).find(|invalid_key| {
+                    let rest = pairs.iter().filter(|(key, _)| key != *invalid_key).cloned();
+                    !matches!(
+                        envy::prefixed(prefix).from_iter::<_, T>(rest),
+                        Err(Error::Custom(m)) if m == message
+                    )
+                });
+
+                match invalid_key {
+                    Some(key) => {
+                        let field = &key[prefix.len()..];
+                        let message = message
+                            .strip_suffix(&format!(" provided by {field}"))
+                            .unwrap_or(&message);
+                        Error::Custom(format!("invalid value for {key}: {message}"))
+                    }
+                    None => Error::Custom(format!("invalid {prefix}* setting: {message}")),
+                }
+            }
+        })
+}
+
+/// `T` のフィールドに対応する、 `prefix` から始まる環境変数のような形式のキー。
+fn prefixed_keys<T: DeserializeOwned>(prefix: &str) -> Vec<String> {
+    /// `deserialize_struct` に渡されたフィールドの名前を覚えるだけで、何も読み込まない [`serde::Deserializer`]
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(
+            self,
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("only structs are supported"))
+        }
+
+        fn deserialize_struct<V: serde::de::Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            self.deserialize_any(visitor)
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+        .iter()
+        .map(|field| format!("{prefix}{}", field.to_uppercase()))
+        .collect()
+}
+
+/// TOML で書かれた設定を、環境変数と同じ形式の組に変換する。
+///
+/// テーブルのキーは `_` で繋げて大文字にする (例: `[db]` の `host` は `DB_HOST`)。
+/// 配列は要素をカンマで繋げた文字列にする。
+/// 書き間違いに気づけるよう、変換したキーが `known_keys` に含まれていなければエラーにする。
+fn env_like_key_value_pairs_from_toml(
+    source: &str,
+    known_keys: &HashSet<String>,
+) -> Result<Vec<(String, String)>> {
+    fn flatten(
+        path: &str,
+        prefix: &str,
+        table: &toml::Table,
+        known_keys: &HashSet<String>,
+        pairs: &mut Vec<(String, String)>,
+    ) -> Result<()> {
+        for (name, value) in table {
+            let path = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{path}.{name}")
+            };
+            let key = format!("{prefix}{}", name.to_uppercase());
+            let value = match value {
+                toml::Value::Table(table) => {
+                    flatten(&path, &format!("{key}_"), table, known_keys, pairs)?;
+                    continue;
+                }
+                toml::Value::Array(values) => values
+                    .iter()
+                    .map(|value| scalar_to_string(&path, value))
+                    .collect::<Result<Vec<_>>>()?
+                    .join(","),
+                value => scalar_to_string(&path, value)?,
+            };
+            if !known_keys.contains(&key) {
+                bail!("unknown setting {path} (corresponds to {key})");
+            }
+            pairs.push((key, value));
+        }
+
+        Ok(())
+    }
+
+    fn scalar_to_string(path: &str, value: &toml::Value) -> Result<String> {
+        Ok(match value {
+            toml::Value::String(string) => string.clone(),
+            toml::Value::Integer(integer) => integer.to_string(),
+            toml::Value::Float(float) => float.to_string(),
+            toml::Value::Boolean(boolean) => boolean.to_string(),
+            toml::Value::Datetime(datetime) => datetime.to_string(),
+            toml::Value::Array(_) | toml::Value::Table(_) => {
+                bail!("{path} must not contain nested arrays or tables")
+            }
+        })
+    }
+
+    let table: toml::Table = toml::from_str(source)?;
+    let mut pairs = vec![];
+    flatten("", "", &table, known_keys, &mut pairs)?;
+    Ok(pairs)
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub database_authorization: DatabaseAuthorizationInfo,
     pub http_config: HttpConfig,
+    pub snapshot_config: SnapshotConfig,
+    pub logging_config: LoggingConfig,
+    pub api_keys_config: ApiKeysConfig,
+    pub rate_limit_config: RateLimitConfig,
+    pub cors_config: CorsConfig,
+    pub graphql_config: GraphqlConfig,
+    pub webhook_config: WebhookConfig,
+    pub image_config: ImageConfig,
+    pub privacy_config: PrivacyConfig,
+    pub rankings_config: RankingsConfig,
 }
 
 impl FromEnvLikeKeyValuePairs for Config {
     fn from_iter(iter: impl Iterator<Item = (String, String)> + Clone) -> Result<Self, Error> {
         Ok(Self {
             database_authorization: DatabaseAuthorizationInfo::from_iter(iter.clone())?,
-            http_config: HttpConfig::from_iter(iter)?,
+            http_config: HttpConfig::from_iter(iter.clone())?,
+            snapshot_config: SnapshotConfig::from_iter(iter.clone())?,
+            logging_config: LoggingConfig::from_iter(iter.clone())?,
+            api_keys_config: ApiKeysConfig::from_iter(iter.clone())?,
+            rate_limit_config: RateLimitConfig::from_iter(iter.clone())?,
+            cors_config: CorsConfig::from_iter(iter.clone())?,
+            graphql_config: GraphqlConfig::from_iter(iter.clone())?,
+            webhook_config: WebhookConfig::from_iter(iter.clone())?,
+            image_config: ImageConfig::from_iter(iter.clone())?,
+            privacy_config: PrivacyConfig::from_iter(iter.clone())?,
+            rankings_config: RankingsConfig::from_iter(iter)?,
         })
     }
+
+    fn keys() -> Vec<String> {
+        [
+            DatabaseAuthorizationInfo::keys(),
+            HttpConfig::keys(),
+            SnapshotConfig::keys(),
+            LoggingConfig::keys(),
+            ApiKeysConfig::keys(),
+            RateLimitConfig::keys(),
+            CorsConfig::keys(),
+            GraphqlConfig::keys(),
+            WebhookConfig::keys(),
+            ImageConfig::keys(),
+            PrivacyConfig::keys(),
+            RankingsConfig::keys(),
+        ]
+        .concat()
+    }
+}
+
+impl Config {
+    /// `file` で指定された TOML ファイルから設定を読み込み、環境変数で上書きする。
+    ///
+    /// `file` が `None` の場合は環境変数のみから読み込む。
+    pub fn load(file: Option<&Path>) -> Result<Self> {
+        let mut pairs = BTreeMap::new();
+
+        if let Some(file) = file {
+            let source = std::fs::read_to_string(file)
+                .with_context(|| format!("failed to read config file {}", file.display()))?;
+            let known_keys = Self::keys().into_iter().collect();
+            pairs.extend(
+                env_like_key_value_pairs_from_toml(&source, &known_keys)
+                    .with_context(|| format!("failed to parse config file {}", file.display()))?,
+            );
+        }
+
+        pairs.extend(std::env::vars());
+
+        let config = Self::from_iter(pairs.into_iter().collect::<Vec<_>>().into_iter())
+            .map_err(anyhow::Error::from);
+        let config = match file {
+            Some(file) => config.with_context(|| {
+                format!(
+                    "failed to load config from {} and environment variables",
+                    file.display()
+                )
+            })?,
+            None => config.context("failed to load config from environment variables")?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 型だけでは表せない制約を検査する。
+    pub fn validate(&self) -> Result<()> {
+        self.http_config.validate()?;
+        self.logging_config.validate()?;
+        self.api_keys_config.validate()?;
+        self.rate_limit_config.validate()?;
+        self.cors_config.validate()?;
+        self.graphql_config.validate()?;
+        self.webhook_config.validate()?;
+        self.rankings_config.validate()
+    }
 }
 
 #[allow(dead_code)]
@@ -42,10 +300,8 @@ pub struct DatabaseAuthorizationInfo {
     pub password: String,
 }
 
-impl FromEnvLikeKeyValuePairs for DatabaseAuthorizationInfo {
-    fn from_iter(iter: impl Iterator<Item = (String, String)>) -> Result<Self, Error> {
-        envy::prefixed("DB_").from_iter(iter)
-    }
+impl PrefixedConfig for DatabaseAuthorizationInfo {
+    const PREFIX: &'static str = "DB_";
 }
 
 #[allow(clippy::module_name_repetitions)]
@@ -53,20 +309,700 @@ impl FromEnvLikeKeyValuePairs for DatabaseAuthorizationInfo {
 pub struct HttpConfig {
     pub host: String,
     pub port: Port,
+    /// PEM 形式の証明書チェーンのパス。 `tls_key_path` と共に指定すると、 HTTP の代わりに HTTPS で待ち受ける。
+    pub tls_cert_path: Option<String>,
+    /// PEM 形式の秘密鍵のパス
+    pub tls_key_path: Option<String>,
+    /// 許可する TLS のバージョン。空の場合は 1.2 と 1.3 の両方を許可する。
+    #[serde(default)]
+    pub tls_versions: Vec<TlsVersion>,
+    /// 終了するときに、処理中のリクエストとランキングの更新が終わるのを待つ最大の時間 (秒)。指定されていない場合は 30 秒。
+    pub shutdown_timeout_secs: Option<u64>,
+    /// `Accept-Encoding` に応じてレスポンスを圧縮するかどうか。指定されていない場合は圧縮する。
+    pub compression: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// HTTPS で待ち受けるための設定
+pub struct TlsConfig<'a> {
+    pub cert_path: &'a Path,
+    pub key_path: &'a Path,
+    pub versions: Vec<TlsVersion>,
+}
+
+impl HttpConfig {
+    const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+    pub fn compression_enabled(&self) -> bool {
+        self.compression.unwrap_or(true)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.shutdown_timeout_secs
+                .unwrap_or(Self::DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+        )
+    }
+
+    /// HTTPS で待ち受ける設定がされていれば、その設定を返す。
+    pub fn tls(&self) -> Option<TlsConfig<'_>> {
+        let (Some(cert_path), Some(key_path)) = (&self.tls_cert_path, &self.tls_key_path) else {
+            return None;
+        };
+
+        let versions = if self.tls_versions.is_empty() {
+            vec![TlsVersion::Tls12, TlsVersion::Tls13]
+        } else {
+            self.tls_versions.clone()
+        };
+
+        Some(TlsConfig {
+            cert_path: Path::new(cert_path),
+            key_path: Path::new(key_path),
+            versions,
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) => {
+                bail!("HTTP_TLS_KEY_PATH must be set when HTTP_TLS_CERT_PATH is set")
+            }
+            (None, Some(_)) => {
+                bail!("HTTP_TLS_CERT_PATH must be set when HTTP_TLS_KEY_PATH is set")
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
 pub struct Port(pub u16);
 
-impl FromEnvLikeKeyValuePairs for HttpConfig {
-    fn from_iter(iter: impl Iterator<Item = (String, String)>) -> Result<Self, Error> {
-        envy::prefixed("HTTP_").from_iter(iter)
+impl PrefixedConfig for HttpConfig {
+    const PREFIX: &'static str = "HTTP_";
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug)]
+pub struct SnapshotConfig {
+    /// ランキングのスナップショットを保存する SQLite データベースのパス。
+    /// 指定されていない場合、スナップショットは保存されない。
+    pub path: Option<String>,
+    /// 同じ種類・集計期間のスナップショットを保存する最小間隔 (秒)。
+    /// 0 の場合、ランキングが更新されるたびに保存する。指定されていない場合は 1 時間。
+    pub interval_secs: Option<u64>,
+    /// スナップショットを残しておく日数。これより古いスナップショットは、新しいスナップショットを保存するときに削除される。
+    /// 0 の場合は削除しない。指定されていない場合は 90 日。
+    pub retention_days: Option<u64>,
+    /// 起動直後から古いランキングを返せるよう、ランキングの更新のたびにすべてのランキングを書き出すファイルのパス。
+    /// 指定されていない場合、ファイルは書き出されず、起動直後は空のランキングを返す。
+    pub state_file: Option<String>,
+}
+
+impl SnapshotConfig {
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+    const DEFAULT_RETENTION_DAYS: u64 = 90;
+    const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+    pub fn interval(&self) -> Duration {
+        self.interval_secs
+            .map_or(Self::DEFAULT_INTERVAL, Duration::from_secs)
+    }
+
+    /// スナップショットを残しておく期間。 `None` の場合は削除しない。
+    pub fn retention(&self) -> Option<Duration> {
+        let days = self.retention_days.unwrap_or(Self::DEFAULT_RETENTION_DAYS);
+        (days > 0).then(|| Duration::from_secs(days.saturating_mul(Self::SECS_PER_DAY)))
+    }
+}
+
+impl PrefixedConfig for SnapshotConfig {
+    const PREFIX: &'static str = "SNAPSHOT_";
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// 人が読むための一行のテキスト
+    #[default]
+    Text,
+    /// 一行に一つの JSON オブジェクト
+    Json,
+}
+
+/// 特定のモジュールについてのログレベルの指定。 `actix_server=warn` のように書く。
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "String")]
+pub struct LogFilter {
+    pub target: String,
+    pub level: LevelFilter,
+}
+
+impl TryFrom<String> for LogFilter {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (target, level) = value
+            .split_once('=')
+            .ok_or_else(|| format!("log filter `{value}` must be in the form of `module=level`"))?;
+        let level = level
+            .parse()
+            .map_err(|_| format!("unknown log level `{level}` in log filter `{value}`"))?;
+
+        Ok(Self {
+            target: target.to_string(),
+            level,
+        })
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug)]
+pub struct LoggingConfig {
+    /// `filters` で指定されていないモジュールのログレベル。指定されていない場合は `info`。
+    #[serde(default = "LoggingConfig::default_level")]
+    pub level: LevelFilter,
+    /// モジュールごとのログレベル
+    #[serde(default)]
+    pub filters: Vec<LogFilter>,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// 標準出力に加えてログを書き出すファイルのパス
+    pub file: Option<String>,
+    /// ログファイルがこの大きさ (バイト) を超えたら、新しいファイルに切り替える。指定されていない場合は 10 MiB。
+    pub file_max_bytes: Option<u64>,
+    /// 切り替えた古いログファイルを残しておく数。指定されていない場合は 5。
+    pub file_max_backups: Option<usize>,
+}
+
+impl LoggingConfig {
+    const DEFAULT_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+    const DEFAULT_FILE_MAX_BACKUPS: usize = 5;
+
+    const fn default_level() -> LevelFilter {
+        LevelFilter::Info
+    }
+
+    pub fn file_max_bytes(&self) -> u64 {
+        self.file_max_bytes.unwrap_or(Self::DEFAULT_FILE_MAX_BYTES)
+    }
+
+    pub fn file_max_backups(&self) -> usize {
+        self.file_max_backups
+            .unwrap_or(Self::DEFAULT_FILE_MAX_BACKUPS)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.file_max_bytes == Some(0) {
+            bail!("LOG_FILE_MAX_BYTES must be positive");
+        }
+        Ok(())
+    }
+}
+
+impl PrefixedConfig for LoggingConfig {
+    const PREFIX: &'static str = "LOG_";
+}
+
+/// API キーに与えられる権限
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, EnumString, Display)]
+pub enum ApiKeyScope {
+    /// ランキングの更新などの管理用 API
+    #[strum(serialize = "admin")]
+    Admin,
+    /// データの書き出し
+    #[strum(serialize = "export")]
+    Export,
+    /// 多数のプレーヤーの一括取得
+    #[strum(serialize = "bulk")]
+    Bulk,
+}
+
+/// `名前:キー:権限+権限` の形式で書かれた API キー。権限は省略できる。
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct ApiKey {
+    /// ログやメトリクスでキーを識別するための名前
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+/// キーそのものがログに出力されないよう、手動で実装している
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .field("key", &"<redacted>")
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl TryFrom<String> for ApiKey {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut parts = value.splitn(3, ':');
+        let (Some(name), Some(key)) = (parts.next(), parts.next()) else {
+            return Err("API key must be in the form of `name:key:scope+scope`".to_string());
+        };
+        if name.is_empty() || key.is_empty() {
+            return Err("API key must have a non-empty name and key".to_string());
+        }
+
+        let scopes = match parts.next() {
+            None | Some("") => vec![],
+            Some(scopes) => scopes
+                .split('+')
+                .map(|scope| {
+                    scope
+                        .parse()
+                        .map_err(|_| format!("unknown scope `{scope}` for API key {name}"))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            key: key.to_string(),
+            scopes,
+        })
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug, Default)]
+pub struct ApiKeysConfig {
+    /// 受け付ける API キー。空の場合、 API キーを必要とするエンドポイントは使えない。
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+}
+
+impl ApiKeysConfig {
+    fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for key in &self.keys {
+            if !names.insert(&key.name) {
+                bail!(
+                    "API key name {} is used more than once in API_KEYS",
+                    key.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PrefixedConfig for ApiKeysConfig {
+    const PREFIX: &'static str = "API_";
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug, Default)]
+pub struct CorsConfig {
+    /// ブラウザからのリクエストを許可するオリジン。 `*` を含む場合はすべてのオリジンを許可する。
+    /// 空の場合、 CORS のヘッダーは付けない。
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// 許可するメソッド。空の場合は `GET` のみを許可する。
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    /// プリフライトリクエストの結果をブラウザがキャッシュしてよい時間 (秒)。指定されていない場合は 3600 秒。
+    pub max_age_secs: Option<usize>,
+}
+
+impl CorsConfig {
+    const DEFAULT_MAX_AGE_SECS: usize = 3600;
+
+    pub fn max_age_secs(&self) -> usize {
+        self.max_age_secs.unwrap_or(Self::DEFAULT_MAX_AGE_SECS)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for origin in &self.allowed_origins {
+            let is_valid_origin = origin == "*"
+                || origin
+                    .parse::<actix_web::http::Uri>()
+                    .is_ok_and(|uri| uri.scheme().is_some() && uri.host().is_some());
+            if !is_valid_origin {
+                bail!(
+                    "{origin} in CORS_ALLOWED_ORIGINS is not an origin such as https://example.com"
+                );
+            }
+        }
+        for method in &self.allowed_methods {
+            if method.parse::<actix_web::http::Method>().is_err() {
+                bail!("{method} in CORS_ALLOWED_METHODS is not an HTTP method");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PrefixedConfig for CorsConfig {
+    const PREFIX: &'static str = "CORS_";
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug, Default)]
+pub struct GraphqlConfig {
+    /// クエリの入れ子の深さの上限。指定されていない場合は 10。
+    pub max_depth: Option<usize>,
+    /// クエリの複雑さ (取得するフィールドの数をリストの件数で重み付けしたもの) の上限。指定されていない場合は 20000。
+    pub max_complexity: Option<usize>,
+}
+
+impl GraphqlConfig {
+    const DEFAULT_MAX_DEPTH: usize = 10;
+    const DEFAULT_MAX_COMPLEXITY: usize = 20_000;
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.unwrap_or(Self::DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn max_complexity(&self) -> usize {
+        self.max_complexity.unwrap_or(Self::DEFAULT_MAX_COMPLEXITY)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.max_depth == Some(0) {
+            bail!("GRAPHQL_MAX_DEPTH must be positive");
+        }
+        if self.max_complexity == Some(0) {
+            bail!("GRAPHQL_MAX_COMPLEXITY must be positive");
+        }
+        Ok(())
+    }
+}
+
+impl PrefixedConfig for GraphqlConfig {
+    const PREFIX: &'static str = "GRAPHQL_";
+}
+
+/// `種類=値` の形式で書かれた、通知する値の節目 (例: `break=100000000`)
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "String")]
+pub struct Milestone {
+    pub kind: AttributionKind,
+    pub value: u64,
+}
+
+impl TryFrom<String> for Milestone {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (kind, threshold) = value
+            .split_once('=')
+            .ok_or_else(|| format!("milestone `{value}` must be in the form of `type=value`"))?;
+
+        Ok(Self {
+            kind: kind
+                .parse()
+                .map_err(|_| format!("unknown type `{kind}` in milestone `{value}`"))?,
+            value: threshold
+                .parse()
+                .map_err(|_| format!("invalid value `{threshold}` in milestone `{value}`"))?,
+        })
+    }
+}
+
+/// Webhook の署名に使う秘密鍵。ログに出力されないよう、 `Debug` を手動で実装している。
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct WebhookSecret(pub String);
+
+impl std::fmt::Debug for WebhookSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug, Default)]
+pub struct WebhookConfig {
+    /// 通知を送る URL。空の場合、通知は送らない。
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// 通知の本文の HMAC-SHA256 署名に使う秘密鍵
+    pub secret: Option<WebhookSecret>,
+    /// いずれかのランキングで1位が入れ替わったときに通知するかどうか
+    #[serde(default)]
+    pub new_leader: bool,
+    /// 指定されている場合、いずれかのランキングでこの順位以内に新しく入ったプレーヤーを通知する
+    pub top_n: Option<u32>,
+    /// 累計 (`all`) のランキングで、値がこれらの節目に達したプレーヤーを通知する
+    #[serde(default)]
+    pub milestones: Vec<Milestone>,
+    /// 一つの通知を送る試行の最大回数。指定されていない場合は 5 回。
+    pub max_attempts: Option<u32>,
+    /// 最初の再試行までの待ち時間 (秒)。再試行のたびに倍になる。指定されていない場合は 1 秒。
+    pub initial_backoff_secs: Option<u64>,
+    /// 一回の送信のタイムアウト (秒)。指定されていない場合は 10 秒。
+    pub timeout_secs: Option<u64>,
+    /// 送れなかった通知を JSON Lines 形式で追記するファイル。指定されていない場合はログにのみ出力する。
+    pub dead_letter_file: Option<String>,
+}
+
+impl WebhookConfig {
+    const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+    const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(Self::DEFAULT_MAX_ATTEMPTS)
+    }
+
+    pub fn initial_backoff(&self) -> Duration {
+        self.initial_backoff_secs
+            .map_or(Self::DEFAULT_INITIAL_BACKOFF, Duration::from_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout_secs
+            .map_or(Self::DEFAULT_TIMEOUT, Duration::from_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !self.urls.is_empty() && self.secret.is_none() {
+            bail!("WEBHOOK_SECRET must be set to send webhooks");
+        }
+        for url in &self.urls {
+            let is_valid_url = url
+                .parse::<actix_web::http::Uri>()
+                .is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")));
+            if !is_valid_url {
+                bail!("{url} in WEBHOOK_URLS is not an http or https URL");
+            }
+        }
+        if self.top_n == Some(0) {
+            bail!("WEBHOOK_TOP_N must be positive");
+        }
+        if self.max_attempts == Some(0) {
+            bail!("WEBHOOK_MAX_ATTEMPTS must be positive");
+        }
+        Ok(())
+    }
+}
+
+impl PrefixedConfig for WebhookConfig {
+    const PREFIX: &'static str = "WEBHOOK_";
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug, Default)]
+pub struct ImageConfig {
+    /// プレーヤーのスキンを `{UUID}.png` という名前で置いておくディレクトリ。
+    /// 指定されていない場合や、スキンが置かれていないプレーヤーについては、画像に顔の代わりに無地の四角形を描く。
+    pub skin_dir: Option<String>,
+    /// PNG の画像を描くときに、システムのフォントに加えて読み込むフォントのディレクトリ
+    pub font_dir: Option<String>,
+    /// PNG の画像を描くときに `sans-serif` として使うフォントファミリー。
+    /// 指定されていない場合は Arial を、 Arial がなければ読み込んだフォントのいずれかを使う。
+    pub font_family: Option<String>,
+}
+
+impl PrefixedConfig for ImageConfig {
+    const PREFIX: &'static str = "IMAGE_";
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug, Default)]
+pub struct PrivacyConfig {
+    /// 名前と最終ログアウト日時を公開しないプレーヤーの UUID。起動時に非公開の一覧に入れられる。
+    #[serde(default)]
+    pub anonymized_players: Vec<Uuid>,
+}
+
+impl PrefixedConfig for PrivacyConfig {
+    const PREFIX: &'static str = "PRIVACY_";
+}
+
+/// 特定のエンドポイントの一回のリクエストで消費するトークンの数。 `/movers=5` のように、ルートのパターンと数を `=` で繋げて書く。
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "String")]
+pub struct EndpointCost {
+    pub pattern: String,
+    pub cost: u32,
+}
+
+impl TryFrom<String> for EndpointCost {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (pattern, cost) = value.rsplit_once('=').ok_or_else(|| {
+            format!("endpoint cost `{value}` must be in the form of `pattern=cost`")
+        })?;
+        let cost = cost
+            .parse()
+            .map_err(|_| format!("invalid cost `{cost}` in endpoint cost `{value}`"))?;
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            cost,
+        })
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug, Default)]
+pub struct RateLimitConfig {
+    /// クライアントごとのトークンバケツの容量。指定されていない場合、レート制限は行わない。
+    pub capacity: Option<u32>,
+    /// 一秒あたりに補充されるトークンの数。指定されていない場合は 1。
+    pub refill_per_sec: Option<f64>,
+    /// `X-Forwarded-For` ヘッダーを信用するリバースプロキシのアドレス
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// エンドポイントごとの一回のリクエストで消費するトークンの数。指定されていないエンドポイントでは 1。
+    #[serde(default)]
+    pub endpoint_costs: Vec<EndpointCost>,
+    /// `limit` クエリパラメータで要求されたこの件数ごとに、消費するトークンが増える。指定されていない場合は 100。
+    pub records_per_token: Option<u32>,
+}
+
+impl RateLimitConfig {
+    const DEFAULT_REFILL_PER_SEC: f64 = 1.0;
+    const DEFAULT_RECORDS_PER_TOKEN: u32 = 100;
+
+    pub fn refill_per_sec(&self) -> f64 {
+        self.refill_per_sec.unwrap_or(Self::DEFAULT_REFILL_PER_SEC)
+    }
+
+    pub fn records_per_token(&self) -> u32 {
+        self.records_per_token
+            .unwrap_or(Self::DEFAULT_RECORDS_PER_TOKEN)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.capacity == Some(0) {
+            bail!("RATE_LIMIT_CAPACITY must be positive");
+        }
+        if self.refill_per_sec().is_nan() || self.refill_per_sec() <= 0.0 {
+            bail!("RATE_LIMIT_REFILL_PER_SEC must be positive");
+        }
+        if self.records_per_token == Some(0) {
+            bail!("RATE_LIMIT_RECORDS_PER_TOKEN must be positive");
+        }
+        Ok(())
+    }
+}
+
+impl PrefixedConfig for RateLimitConfig {
+    const PREFIX: &'static str = "RATE_LIMIT_";
+}
+
+/// ある種類のランキングについての設定
+#[derive(Deserialize, Debug, Default)]
+pub struct AttributionRankingConfig {
+    /// ランキングを更新する間隔 (秒)。指定されていない場合は 120 秒。
+    pub refresh_interval_secs: Option<u64>,
+    /// ランキングに含めないプレーヤーの UUID
+    #[serde(default)]
+    pub excluded_players: Vec<Uuid>,
+    /// 一度のリクエストで取得できるレコードの最大件数。指定されていない場合は 1000 件。
+    pub max_limit: Option<usize>,
+}
+
+impl AttributionRankingConfig {
+    const DEFAULT_REFRESH_INTERVAL_SECS: u64 = 120;
+    const DEFAULT_MAX_LIMIT: usize = 1000;
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.refresh_interval_secs
+                .unwrap_or(Self::DEFAULT_REFRESH_INTERVAL_SECS),
+        )
+    }
+
+    pub fn max_limit(&self) -> usize {
+        self.max_limit.unwrap_or(Self::DEFAULT_MAX_LIMIT)
+    }
+
+    fn validate(&self, prefix: &str) -> Result<()> {
+        if self.refresh_interval_secs == Some(0) {
+            bail!("{prefix}REFRESH_INTERVAL_SECS must be positive");
+        }
+        if self.max_limit == Some(0) {
+            bail!("{prefix}MAX_LIMIT must be positive");
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Deserialize, Debug, Default)]
+pub struct RankingsConfig {
+    pub break_count: AttributionRankingConfig,
+    pub build_count: AttributionRankingConfig,
+    pub play_ticks: AttributionRankingConfig,
+    pub vote_count: AttributionRankingConfig,
+}
+
+impl RankingsConfig {
+    const BREAK_COUNT_PREFIX: &'static str = "RANKINGS_BREAK_";
+    const BUILD_COUNT_PREFIX: &'static str = "RANKINGS_BUILD_";
+    const PLAY_TICKS_PREFIX: &'static str = "RANKINGS_PLAY_TICKS_";
+    const VOTE_COUNT_PREFIX: &'static str = "RANKINGS_VOTE_COUNT_";
+
+    pub const fn for_kind(&self, kind: AttributionKind) -> &AttributionRankingConfig {
+        match kind {
+            AttributionKind::Break => &self.break_count,
+            AttributionKind::Build => &self.build_count,
+            AttributionKind::PlayTicks => &self.play_ticks,
+            AttributionKind::VoteCount => &self.vote_count,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.break_count.validate(Self::BREAK_COUNT_PREFIX)?;
+        self.build_count.validate(Self::BUILD_COUNT_PREFIX)?;
+        self.play_ticks.validate(Self::PLAY_TICKS_PREFIX)?;
+        self.vote_count.validate(Self::VOTE_COUNT_PREFIX)
+    }
+}
+
+impl FromEnvLikeKeyValuePairs for RankingsConfig {
+    fn from_iter(iter: impl Iterator<Item = (String, String)> + Clone) -> Result<Self, Error> {
+        Ok(Self {
+            break_count: from_prefixed_iter(Self::BREAK_COUNT_PREFIX, iter.clone())?,
+            build_count: from_prefixed_iter(Self::BUILD_COUNT_PREFIX, iter.clone())?,
+            play_ticks: from_prefixed_iter(Self::PLAY_TICKS_PREFIX, iter.clone())?,
+            vote_count: from_prefixed_iter(Self::VOTE_COUNT_PREFIX, iter)?,
+        })
+    }
+
+    fn keys() -> Vec<String> {
+        [
+            Self::BREAK_COUNT_PREFIX,
+            Self::BUILD_COUNT_PREFIX,
+            Self::PLAY_TICKS_PREFIX,
+            Self::VOTE_COUNT_PREFIX,
+        ]
+        .into_iter()
+        .flat_map(prefixed_keys::<AttributionRankingConfig>)
+        .collect()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::config::{Config, FromEnvLikeKeyValuePairs};
+    use crate::config::{
+        env_like_key_value_pairs_from_toml, ApiKey, ApiKeyScope, Config, FromEnvLikeKeyValuePairs,
+        LogFilter, LogFormat, Port,
+    };
+    use log::LevelFilter;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use uuid::Uuid;
 
     #[test]
     fn read_config_from_iterator() {
@@ -81,4 +1017,157 @@ mod test {
 
         Config::from_iter(setting.into_iter()).unwrap();
     }
+
+    #[test]
+    fn read_config_from_toml_overridden_by_env_like_pairs() {
+        let file = r#"
+            [http]
+            host = "127.0.0.1"
+            port = 8080
+
+            [db]
+            host = "example.com"
+            port = 3306
+            user = "bff"
+            password = "from-file"
+
+            [log]
+            level = "warn"
+            filters = ["seichi_ranking_bff=debug", "actix_server=error"]
+            format = "json"
+
+            [api]
+            keys = ["ops:s3cr3t:admin+export", "partner:p4ssw0rd"]
+
+            [rankings.play_ticks]
+            refresh_interval_secs = 600
+            excluded_players = [
+                "00000000-0000-0000-0000-000000000001",
+                "00000000-0000-0000-0000-000000000002",
+            ]
+
+            [privacy]
+            anonymized_players = ["00000000-0000-0000-0000-000000000003"]
+        "#;
+
+        let known_keys = Config::keys().into_iter().collect();
+        let mut pairs = env_like_key_value_pairs_from_toml(file, &known_keys).unwrap();
+        pairs.push(("DB_PASSWORD".to_string(), "from-env".to_string()));
+        let pairs = pairs.into_iter().collect::<BTreeMap<_, _>>();
+
+        let config = Config::from_iter(pairs.into_iter().collect::<Vec<_>>().into_iter()).unwrap();
+
+        assert_eq!(config.http_config.port, Port(8080));
+        assert_eq!(config.database_authorization.password, "from-env");
+        assert_eq!(config.logging_config.level, LevelFilter::Warn);
+        assert_eq!(
+            config.logging_config.filters,
+            vec![
+                LogFilter {
+                    target: "seichi_ranking_bff".to_string(),
+                    level: LevelFilter::Debug,
+                },
+                LogFilter {
+                    target: "actix_server".to_string(),
+                    level: LevelFilter::Error,
+                },
+            ]
+        );
+        assert_eq!(config.logging_config.format, LogFormat::Json);
+        assert_eq!(
+            config.api_keys_config.keys,
+            vec![
+                ApiKey {
+                    name: "ops".to_string(),
+                    key: "s3cr3t".to_string(),
+                    scopes: vec![ApiKeyScope::Admin, ApiKeyScope::Export],
+                },
+                ApiKey {
+                    name: "partner".to_string(),
+                    key: "p4ssw0rd".to_string(),
+                    scopes: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            config.rankings_config.play_ticks.refresh_interval(),
+            Duration::from_secs(600)
+        );
+        assert_eq!(
+            config.rankings_config.play_ticks.excluded_players,
+            vec![Uuid::from_u128(1), Uuid::from_u128(2)]
+        );
+        assert!(config
+            .rankings_config
+            .break_count
+            .excluded_players
+            .is_empty());
+        assert_eq!(
+            config.privacy_config.anonymized_players,
+            vec![Uuid::from_u128(3)]
+        );
+    }
+
+    #[test]
+    fn invalid_value_error_names_the_key() {
+        let setting = [
+            ("HTTP_PORT".to_string(), "12345".to_string()),
+            ("HTTP_HOST".to_string(), "127.0.0.1".to_string()),
+            ("DB_HOST".to_string(), "example.com".to_string()),
+            ("DB_USER".to_string(), "bff".to_string()),
+            ("DB_PASSWORD".to_string(), "$tr0ngpAssw0rd".to_string()),
+        ];
+
+        let error = Config::from_iter(setting.clone().into_iter()).unwrap_err();
+        assert_eq!(error.to_string(), "missing value for DB_PORT");
+
+        let error_with = |key: &str, value: &str| {
+            let setting = setting
+                .iter()
+                .cloned()
+                .chain([("DB_PORT".to_string(), "3306".to_string())])
+                .chain([(key.to_string(), value.to_string())])
+                .collect::<BTreeMap<_, _>>();
+            Config::from_iter(setting.into_iter().collect::<Vec<_>>().into_iter())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error_with("HTTP_PORT", "http"),
+            "invalid value for HTTP_PORT: invalid digit found in string while parsing value 'http'"
+        );
+        assert!(
+            error_with("RANKINGS_PLAY_TICKS_EXCLUDED_PLAYERS", "not-a-uuid")
+                .starts_with("invalid value for RANKINGS_PLAY_TICKS_EXCLUDED_PLAYERS: "),
+            "{}",
+            error_with("RANKINGS_PLAY_TICKS_EXCLUDED_PLAYERS", "not-a-uuid")
+        );
+    }
+
+    #[test]
+    fn reject_unknown_keys_in_toml() {
+        let known_keys = Config::keys().into_iter().collect();
+        let file = r#"
+            [http]
+            host = "127.0.0.1"
+            prot = 8080
+        "#;
+
+        let error = env_like_key_value_pairs_from_toml(file, &known_keys).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unknown setting http.prot (corresponds to HTTP_PROT)"
+        );
+
+        let file = r#"
+            [rankings.play_tick]
+            refresh_interval_secs = 600
+        "#;
+        let error = env_like_key_value_pairs_from_toml(file, &known_keys).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unknown setting rankings.play_tick.refresh_interval_secs \
+             (corresponds to RANKINGS_PLAY_TICK_REFRESH_INTERVAL_SECS)"
+        );
+    }
 }
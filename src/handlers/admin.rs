@@ -0,0 +1,358 @@
+use crate::app_models::{AppState, Rehydrator};
+use crate::auth::{require_scope, ApiKeyUsage};
+use crate::config::ApiKeyScope;
+use crate::handlers::presentation_models::{RehydrationReport, RehydrationResult};
+use crate::handlers::ranking::{duration_not_recognized_response, unknown_attribution_kind};
+use crate::models::{AggregationTimeRange, AttributionKind};
+use actix_web::http::header::ContentType;
+use actix_web::web::Path;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::{error, info};
+use qstring::QString;
+use std::str::FromStr;
+use strum::IntoEnumIterator;
+use uuid::Uuid;
+
+/// ランキングをすぐに更新する。
+///
+/// `type` が指定されていなければすべての種類を、`time_range` が指定されていなければすべての集計期間を更新する。
+/// 定期的な更新が進行中であれば、それが終わるのを待ってから更新する。
+/// 状態ファイルが設定されていれば、定期的な更新と同じく更新後に書き出す。
+#[utoipa::path(
+    post,
+    path = "/admin/rehydrate",
+    params(
+        ("type" = Option<AttributionKind>, Query, description = "更新するランキングの種類。指定しない場合はすべて"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "更新する集計期間。指定しない場合はすべて"),
+    ),
+    responses(
+        (status = 200, body = RehydrationReport),
+        (status = 401, description = "API キーが付いていない"),
+        (status = 403, description = "API キーに `admin` 権限がない"),
+        (status = 500, body = RehydrationReport, description = "いずれかの更新に失敗した"),
+    ),
+    security(("api_key" = ["admin"]))
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::post("/admin/rehydrate")]
+pub async fn rehydrate(req: HttpRequest, rehydrator: web::Data<Rehydrator>) -> impl Responder {
+    if let Some(response) = require_scope(&req, ApiKeyScope::Admin) {
+        return response;
+    }
+
+    let qs: QString = req.query_string().into();
+
+    let time_range = match qs.get("time_range") {
+        None => None,
+        Some(specifier) => match AggregationTimeRange::from_str(specifier) {
+            Ok(r) => Some(r),
+            Err(_) => return duration_not_recognized_response(specifier),
+        },
+    };
+
+    let kinds = match qs.get("type") {
+        None => AttributionKind::iter().collect(),
+        Some(specifier) => match AttributionKind::from_str(specifier) {
+            Ok(kind) => vec![kind],
+            Err(_) => return unknown_attribution_kind(specifier),
+        },
+    };
+
+    let mut results = vec![];
+    for kind in kinds {
+        info!("Rehydrating ranking cache on request (kind={kind})");
+        let result = rehydrator.rehydrate_kind(kind, time_range).await;
+
+        if let Err(e) = &result {
+            error!("Error rehydrating ranking cache on request (kind={kind}): {e}");
+        }
+
+        results.push(RehydrationResult {
+            kind: kind.to_string(),
+            succeeded: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+
+    // 定期的な更新と同じく、更新したランキングを状態ファイルにも書き出しておく
+    if results.iter().any(|result| result.succeeded) {
+        if let Err(e) = rehydrator.save_state().await {
+            error!("Error saving state: {e:#}");
+        }
+    }
+
+    let report = RehydrationReport {
+        succeeded: results.iter().all(|result| result.succeeded),
+        time_range: time_range.map(|time_range| time_range.to_string()),
+        results,
+    };
+
+    if report.succeeded {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::InternalServerError().json(report)
+    }
+}
+
+/// API キーごとの利用回数を Prometheus のテキスト形式で返す。
+#[utoipa::path(
+    get,
+    path = "/admin/metrics",
+    responses(
+        (status = 200, body = String, content_type = "text/plain"),
+        (status = 401, description = "API キーが付いていない"),
+        (status = 403, description = "API キーに `admin` 権限がない"),
+    ),
+    security(("api_key" = ["admin"]))
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/admin/metrics")]
+pub async fn metrics(req: HttpRequest, usage: web::Data<ApiKeyUsage>) -> impl Responder {
+    if let Some(response) = require_scope(&req, ApiKeyScope::Admin) {
+        return response;
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(usage.to_prometheus_text())
+}
+
+/// 名前と最終ログアウト日時を公開しないプレーヤーの UUID を返す。
+#[utoipa::path(
+    get,
+    path = "/admin/privacy",
+    responses(
+        (status = 200, body = [Uuid]),
+        (status = 401, description = "API キーが付いていない"),
+        (status = 403, description = "API キーに `admin` 権限がない"),
+    ),
+    security(("api_key" = ["admin"]))
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/admin/privacy")]
+pub async fn privacy_list(req: HttpRequest, data: web::Data<&'static AppState>) -> impl Responder {
+    if let Some(response) = require_scope(&req, ApiKeyScope::Admin) {
+        return response;
+    }
+
+    HttpResponse::Ok().json(data.privacy_list.players())
+}
+
+/// プレーヤーの名前と最終ログアウト日時を公開しないようにする。
+///
+/// 変更はサーバーが再起動されるまでしか保たれないので、続けて非公開にするには設定にも加える必要がある。
+#[utoipa::path(
+    put,
+    path = "/admin/privacy/{uuid}",
+    params(
+        ("uuid" = Uuid, Path, description = "プレーヤーの UUID"),
+    ),
+    responses(
+        (status = 204, description = "非公開にした (すでに非公開だった場合を含む)"),
+        (status = 401, description = "API キーが付いていない"),
+        (status = 403, description = "API キーに `admin` 権限がない"),
+    ),
+    security(("api_key" = ["admin"]))
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::put("/admin/privacy/{uuid}")]
+pub async fn anonymize_player(
+    req: HttpRequest,
+    path: Path<Uuid>,
+    data: web::Data<&'static AppState>,
+) -> impl Responder {
+    if let Some(response) = require_scope(&req, ApiKeyScope::Admin) {
+        return response;
+    }
+
+    let player_uuid = path.into_inner();
+    if data.privacy_list.insert(player_uuid) {
+        info!("Player {player_uuid} was added to the privacy list");
+    }
+    HttpResponse::NoContent().finish()
+}
+
+/// プレーヤーの名前と最終ログアウト日時を再び公開する。
+#[utoipa::path(
+    delete,
+    path = "/admin/privacy/{uuid}",
+    params(
+        ("uuid" = Uuid, Path, description = "プレーヤーの UUID"),
+    ),
+    responses(
+        (status = 204, description = "公開するようにした"),
+        (status = 401, description = "API キーが付いていない"),
+        (status = 403, description = "API キーに `admin` 権限がない"),
+        (status = 404, description = "プレーヤーが非公開の一覧に含まれていない"),
+    ),
+    security(("api_key" = ["admin"]))
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::delete("/admin/privacy/{uuid}")]
+pub async fn unanonymize_player(
+    req: HttpRequest,
+    path: Path<Uuid>,
+    data: web::Data<&'static AppState>,
+) -> impl Responder {
+    if let Some(response) = require_scope(&req, ApiKeyScope::Admin) {
+        return response;
+    }
+
+    let player_uuid = path.into_inner();
+    if data.privacy_list.remove(player_uuid) {
+        info!("Player {player_uuid} was removed from the privacy list");
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().body(format!("{player_uuid} is not in the privacy list"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::{AppState, LazyAttributionRecordProviders, Rehydrator};
+    use crate::auth::{ApiKeyAuthentication, ApiKeyUsage};
+    use crate::config::{ApiKey, ApiKeyScope, RankingsConfig};
+    use crate::handlers::admin::{anonymize_player, privacy_list, rehydrate, unanonymize_player};
+    use crate::models::AggregationTimeRange;
+    use crate::test_fixtures::single_player_providers;
+    use actix_web::http::StatusCode;
+    use actix_web::web::Data;
+    use actix_web::{test, App};
+    use std::sync::{Arc, LazyLock};
+    use uuid::Uuid;
+
+    static PROVIDERS: LazyAttributionRecordProviders = LazyLock::new(single_player_providers);
+
+    #[actix_web::test]
+    async fn rehydrate_only_with_admin_api_key() {
+        let state: &'static AppState = Box::leak(Box::default());
+        let state_file =
+            std::env::temp_dir().join(format!("admin-rehydrate-{}.json.gz", std::process::id()));
+        let keys = Arc::new(vec![
+            ApiKey {
+                name: "ops".to_string(),
+                key: "secret".to_string(),
+                scopes: vec![ApiKeyScope::Admin],
+            },
+            ApiKey {
+                name: "partner".to_string(),
+                key: "exporter".to_string(),
+                scopes: vec![ApiKeyScope::Export],
+            },
+        ]);
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Rehydrator {
+                    state,
+                    providers: &PROVIDERS,
+                    rankings_config: Arc::new(RankingsConfig::default()),
+                    snapshot_store: None,
+                    state_file: Some(state_file.clone()),
+                }))
+                .service(rehydrate)
+                .wrap(ApiKeyAuthentication::new(
+                    keys,
+                    Arc::new(ApiKeyUsage::new(&[])),
+                )),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/admin/rehydrate?type=break")
+            .insert_header(("Authorization", "Bearer wrong"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::post()
+            .uri("/admin/rehydrate?type=break")
+            .insert_header(("X-Api-Key", "exporter"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let request = test::TestRequest::post()
+            .uri("/admin/rehydrate?type=break&time_range=week")
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let rehydrated = |time_range| async move {
+            state
+                .break_count_rankings
+                .for_time_range(time_range)
+                .read()
+                .await
+                .record_with_uuid(Uuid::from_u128(1))
+                .is_some()
+        };
+        assert!(rehydrated(AggregationTimeRange::LastOneWeek).await);
+        assert!(!rehydrated(AggregationTimeRange::All).await);
+
+        // 更新したランキングは状態ファイルにも書き出される
+        let restored = AppState::default();
+        restored.restore_from_file(&state_file).await.unwrap();
+        std::fs::remove_file(&state_file).unwrap();
+        assert!(restored
+            .break_count_rankings
+            .for_time_range(AggregationTimeRange::LastOneWeek)
+            .read()
+            .await
+            .record_with_uuid(Uuid::from_u128(1))
+            .is_some());
+    }
+
+    #[actix_web::test]
+    async fn add_and_remove_players_from_privacy_list() {
+        let state: &'static AppState = Box::leak(Box::default());
+        let keys = Arc::new(vec![ApiKey {
+            name: "ops".to_string(),
+            key: "secret".to_string(),
+            scopes: vec![ApiKeyScope::Admin],
+        }]);
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .service(privacy_list)
+                .service(anonymize_player)
+                .service(unanonymize_player)
+                .wrap(ApiKeyAuthentication::new(
+                    keys,
+                    Arc::new(ApiKeyUsage::new(&[])),
+                )),
+        )
+        .await;
+        let player_uuid = Uuid::from_u128(1);
+        let uri = format!("/admin/privacy/{player_uuid}");
+
+        let request = test::TestRequest::put().uri(&uri).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(!state.privacy_list.contains(player_uuid));
+
+        let request = test::TestRequest::put()
+            .uri(&uri)
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let request = test::TestRequest::get()
+            .uri("/admin/privacy")
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request();
+        let players: Vec<Uuid> = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(players, vec![player_uuid]);
+
+        for expected_status in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+            let request = test::TestRequest::delete()
+                .uri(&uri)
+                .insert_header(("Authorization", "Bearer secret"))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), expected_status);
+        }
+        assert!(!state.privacy_list.contains(player_uuid));
+    }
+}
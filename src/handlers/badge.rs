@@ -0,0 +1,330 @@
+use crate::app_models::{AppState, LockedRankingsForTimeRanges};
+use crate::handlers::images::{escape_xml, FONT_FAMILY};
+use crate::handlers::ranking::{
+    duration_not_recognized_response, time_range_from_qs, unknown_attribution_kind,
+};
+use crate::models::{AggregatedPlayerAttribution, AggregationTimeRange, AttributionKind};
+use actix_web::http::header::{CacheControl, CacheDirective, ETag, EntityTag, IfNoneMatch};
+use actix_web::web::Path;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use qstring::QString;
+use std::fmt::Write;
+use std::str::FromStr;
+use strum::EnumString;
+use uuid::Uuid;
+
+/// 左側のラベルとして指定できる最大の文字数
+const MAX_LABEL_CHARS: usize = 32;
+
+/// バッジを CDN やブラウザにキャッシュさせてよい時間 (秒)。
+/// これを過ぎた後は `ETag` で再検証させ、ランキングが更新されていなければ `304` を返す。
+const BADGE_MAX_AGE_SECS: u32 = 60;
+
+/// バッジの見た目。名前は shields.io のスタイルに合わせている。
+#[derive(Clone, Copy, PartialEq, Eq, EnumString)]
+enum BadgeStyle {
+    /// 角が丸く、上から下へ薄く陰影が付く
+    #[strum(serialize = "flat")]
+    Flat,
+    /// 角が四角く、陰影がない
+    #[strum(serialize = "flat-square")]
+    FlatSquare,
+    /// 背が高く、太字で英字が大文字になる
+    #[strum(serialize = "for-the-badge")]
+    ForTheBadge,
+}
+
+struct BadgeMetrics {
+    height: u32,
+    font_size: u32,
+    /// 文字列の左右の余白
+    padding: u32,
+    text_y: u32,
+    /// ASCII の文字と、それ以外 (主に全角) の文字の幅の目安
+    ascii_char_width: u32,
+    wide_char_width: u32,
+    radius: u32,
+}
+
+impl BadgeStyle {
+    const fn metrics(self) -> BadgeMetrics {
+        match self {
+            Self::Flat | Self::FlatSquare => BadgeMetrics {
+                height: 20,
+                font_size: 11,
+                padding: 6,
+                text_y: 14,
+                ascii_char_width: 7,
+                wide_char_width: 11,
+                radius: if matches!(self, Self::Flat) { 3 } else { 0 },
+            },
+            Self::ForTheBadge => BadgeMetrics {
+                height: 28,
+                font_size: 10,
+                padding: 10,
+                text_y: 18,
+                ascii_char_width: 8,
+                wide_char_width: 12,
+                radius: 0,
+            },
+        }
+    }
+}
+
+/// バッジの左側に出す、ランキングの種類の短い名前
+const fn short_kind_label(kind: AttributionKind) -> &'static str {
+    match kind {
+        AttributionKind::Break => "整地",
+        AttributionKind::Build => "建築",
+        AttributionKind::PlayTicks => "プレイ時間",
+        AttributionKind::VoteCount => "投票",
+    }
+}
+
+/// 順位に応じた、バッジの右側の色
+const fn rank_color(rank: Option<u32>) -> &'static str {
+    match rank {
+        Some(1..=10) => "#dfb317",
+        Some(11..=100) => "#44cc11",
+        Some(_) => "#007ec6",
+        None => "#9f9f9f",
+    }
+}
+
+/// 描画されたときの文字列の幅の目安。フォントを読まずに決めるので、実際の幅とは多少ずれる。
+fn text_width(text: &str, metrics: &BadgeMetrics) -> u32 {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii() {
+                metrics.ascii_char_width
+            } else {
+                metrics.wide_char_width
+            }
+        })
+        .sum()
+}
+
+/// 左側に `label`、右側に `color` の背景で `message` を書いたバッジの SVG。
+fn render_badge(label: &str, message: &str, color: &str, style: BadgeStyle) -> String {
+    let metrics = style.metrics();
+    let (label, message) = match style {
+        BadgeStyle::ForTheBadge => (label.to_uppercase(), message.to_uppercase()),
+        BadgeStyle::Flat | BadgeStyle::FlatSquare => (label.to_string(), message.to_string()),
+    };
+    let label_width = text_width(&label, &metrics) + metrics.padding * 2;
+    let message_width = text_width(&message, &metrics) + metrics.padding * 2;
+    let width = label_width + message_width;
+    let height = metrics.height;
+    let (label, message) = (escape_xml(&label), escape_xml(&message));
+
+    let mut svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><clipPath id="r"><rect width="{width}" height="{height}" rx="{}" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="{height}" fill="#555"/><rect x="{label_width}" width="{message_width}" height="{height}" fill="{color}"/>"##,
+        metrics.radius
+    );
+    if style == BadgeStyle::Flat {
+        let _ = write!(
+            svg,
+            r##"<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><rect width="{width}" height="{height}" fill="url(#s)"/>"##
+        );
+    }
+    let text_attributes = match style {
+        BadgeStyle::ForTheBadge => r#" font-weight="bold" letter-spacing="1""#,
+        BadgeStyle::Flat | BadgeStyle::FlatSquare => "",
+    };
+    let _ = write!(
+        svg,
+        r##"</g><g fill="#fff" text-anchor="middle" font-family="{FONT_FAMILY}" font-size="{}"{text_attributes}><text x="{}" y="{}">{label}</text><text x="{}" y="{}">{message}</text></g></svg>"##,
+        metrics.font_size,
+        label_width / 2,
+        metrics.text_y,
+        label_width + message_width / 2,
+        metrics.text_y
+    );
+    svg
+}
+
+/// プレーヤーの順位を、ランキングの世代番号と共に取り出す。プレーヤーがランキングに含まれていない場合、順位は `None` になる。
+async fn rank_with_generation<Attribution: AggregatedPlayerAttribution>(
+    rankings: &LockedRankingsForTimeRanges<Attribution>,
+    time_range: AggregationTimeRange,
+    uuid: Uuid,
+) -> (u64, Option<u32>) {
+    let ranking = rankings.for_time_range(time_range).read().await;
+    let rank = ranking.record_with_uuid(uuid).map(|record| record.rank);
+
+    (ranking.generation(), rank)
+}
+
+/// バッジの `ETag`。
+///
+/// バッジの中身は URL のほかには順位にしか依らないので、ランキングの世代番号と順位から作る。
+/// 状態ファイルなしで再起動すると世代番号は最初からになるが、順位も含めているので、中身が変われば `ETag` も変わる。
+fn badge_entity_tag(generation: u64, rank: Option<u32>) -> EntityTag {
+    EntityTag::new_strong(format!("{generation}-{}", rank.unwrap_or(0)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/player-ranks/{uuid}/badge",
+    params(
+        ("uuid" = Uuid, Path, description = "プレーヤーの UUID"),
+        ("type" = Option<AttributionKind>, Query, description = "ランキングの種類。既定値は `break`"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "集計期間。既定値は `all`"),
+        ("style" = Option<String>, Query, description = "`flat`、 `flat-square` または `for-the-badge`。既定値は `flat`"),
+        ("label" = Option<String>, Query, description = "左側に出す文字列。既定値はランキングの種類の短い名前 (`整地` など)"),
+        ("If-None-Match" = Option<String>, Header, description = "前回受け取った `ETag`。ランキングが更新されていなければ `304` を返す"),
+    ),
+    responses(
+        (status = 200, content_type = "image/svg+xml", description = "プレーヤーの順位のバッジ。ランキングに含まれていない場合は `圏外` と書かれる"),
+        (status = 304, description = "前回からランキングが更新されていない"),
+        (status = 400, description = "クエリパラメータが不正"),
+    )
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/player-ranks/{uuid}/badge")]
+pub async fn player_rank_badge(
+    req: HttpRequest,
+    path: Path<Uuid>,
+    data: web::Data<&'static AppState>,
+) -> impl Responder {
+    let qs: QString = req.query_string().into();
+
+    let time_range_specifier = time_range_from_qs(&qs);
+    let time_range = match AggregationTimeRange::from_str(time_range_specifier) {
+        Ok(r) => r,
+        Err(_) => return duration_not_recognized_response(time_range_specifier),
+    };
+
+    let attribution_kind = qs.get("type").unwrap_or("break");
+    let Ok(kind) = AttributionKind::from_str(attribution_kind) else {
+        return unknown_attribution_kind(attribution_kind);
+    };
+
+    let style_specifier = qs.get("style").unwrap_or("flat");
+    let Ok(style) = BadgeStyle::from_str(style_specifier) else {
+        return HttpResponse::BadRequest().body(format!(
+            "{style_specifier} is not a recognized badge style."
+        ));
+    };
+
+    let label = qs.get("label").unwrap_or(short_kind_label(kind));
+    if label.chars().count() > MAX_LABEL_CHARS {
+        return HttpResponse::BadRequest().body(format!(
+            "label must be at most {MAX_LABEL_CHARS} characters long"
+        ));
+    }
+
+    let player_uuid = path.into_inner();
+
+    let (generation, rank) = match kind {
+        AttributionKind::Break => {
+            rank_with_generation(&data.break_count_rankings, time_range, player_uuid).await
+        }
+        AttributionKind::Build => {
+            rank_with_generation(&data.build_count_rankings, time_range, player_uuid).await
+        }
+        AttributionKind::PlayTicks => {
+            rank_with_generation(&data.play_ticks_rankings, time_range, player_uuid).await
+        }
+        AttributionKind::VoteCount => {
+            rank_with_generation(&data.vote_count_rankings, time_range, player_uuid).await
+        }
+    };
+
+    let entity_tag = badge_entity_tag(generation, rank);
+    let cache_control = CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(BADGE_MAX_AGE_SECS),
+    ]);
+
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&entity_tag)),
+        None => false,
+    };
+    if not_modified {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(entity_tag))
+            .insert_header(cache_control)
+            .finish();
+    }
+
+    let message = match rank {
+        Some(rank) => format!("#{rank} ({time_range})"),
+        None => format!("圏外 ({time_range})"),
+    };
+    HttpResponse::Ok()
+        .content_type("image/svg+xml")
+        .insert_header(ETag(entity_tag))
+        .insert_header(cache_control)
+        .body(render_badge(label, &message, rank_color(rank), style))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::AppState;
+    use crate::handlers::badge::player_rank_badge;
+    use crate::models::AggregationTimeRange;
+    use crate::test_fixtures::records_with_values;
+    use actix_web::http::header;
+    use actix_web::http::StatusCode;
+    use actix_web::web::Data;
+    use actix_web::{test, App};
+    use uuid::Uuid;
+
+    async fn hydrate_break_count_ranking(state: &AppState, values: &[u64]) {
+        state
+            .break_count_rankings
+            .for_time_range(AggregationTimeRange::LastOneMonth)
+            .write()
+            .await
+            .hydrate_record_set(records_with_values(values));
+    }
+
+    #[actix_web::test]
+    async fn revalidate_badge_until_ranking_is_updated() {
+        let state: &'static AppState = Box::leak(Box::default());
+        hydrate_break_count_ranking(state, &[10, 20]).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .service(player_rank_badge),
+        )
+        .await;
+        let uri = format!(
+            "/player-ranks/{}/badge?time_range=month",
+            Uuid::from_u128(1)
+        );
+
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+        assert_eq!(
+            response.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=60"
+        );
+        let body = test::read_body(response).await;
+        let svg = std::str::from_utf8(&body).unwrap();
+        assert!(svg.contains(">整地</text>"), "{svg}");
+        assert!(svg.contains(">#2 (month)</text>"), "{svg}");
+
+        let revalidate = || {
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::IF_NONE_MATCH, etag.clone()))
+                .to_request()
+        };
+        let response = test::call_service(&app, revalidate()).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        hydrate_break_count_ranking(state, &[30, 20]).await;
+        let response = test::call_service(&app, revalidate()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers().get(header::ETAG).unwrap(), etag);
+        let body = test::read_body(response).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains(">#1 (month)</text>"));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::app_models::{AppState, PlayerSummary, PrivacyList};
+use crate::handlers::formatting::{
+    format_value, kind_label, locale_from_request, time_range_label, Locale,
+};
+use crate::handlers::images::{
+    escape_xml, face_svg, image_format_not_recognized_response, image_response, ImageFormat,
+    Rasterizer, FONT_FAMILY,
+};
+use crate::handlers::presentation_models::displayed_player_name;
+use crate::handlers::ranking::{duration_not_recognized_response, time_range_from_qs};
+use crate::models::{AggregationTimeRange, AttributionKind};
+use crate::skin_store::SkinStore;
+use actix_web::http::header::{self, CacheControl, CacheDirective};
+use actix_web::web::Path;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::warn;
+use qstring::QString;
+use std::fmt::Write;
+use std::str::FromStr;
+use strum::IntoEnumIterator;
+use uuid::Uuid;
+
+const CARD_WIDTH: u32 = 480;
+const FACE_SIZE: u32 = 72;
+const PADDING: u32 = 24;
+const ROWS_TOP: u32 = 132;
+const ROW_HEIGHT: u32 = 24;
+
+/// カードを CDN やブラウザにキャッシュさせてよい時間 (秒)
+const CARD_MAX_AGE_SECS: u32 = 60;
+
+/// プレーヤーの名前と顔、 `time_range` のすべての種類のランキングでの順位と値を並べたカードの SVG。
+fn render_card(
+    summary: &PlayerSummary,
+    privacy_list: &PrivacyList,
+    time_range: AggregationTimeRange,
+    skin: Option<&[u8]>,
+    locale: Locale,
+) -> String {
+    let height = ROWS_TOP + ROW_HEIGHT * u32::try_from(AttributionKind::iter().count()).unwrap();
+    let text_left = PADDING * 2 + FACE_SIZE;
+    let right = CARD_WIDTH - PADDING;
+
+    let mut svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{CARD_WIDTH}" height="{height}" viewBox="0 0 {CARD_WIDTH} {height}" font-family="{FONT_FAMILY}"><rect width="{CARD_WIDTH}" height="{height}" rx="12" fill="#1f2330"/>"##
+    );
+    svg.push_str(&face_svg(skin, PADDING, PADDING, FACE_SIZE));
+    let _ = write!(
+        svg,
+        r##"<text x="{text_left}" y="{}" font-size="26" font-weight="bold" fill="#ffffff">{}</text><text x="{text_left}" y="{}" font-size="14" fill="#a0a8b8">{}</text>"##,
+        PADDING + 32,
+        escape_xml(displayed_player_name(&summary.player, privacy_list)),
+        PADDING + 58,
+        time_range_label(time_range, locale)
+    );
+
+    for (kind, y) in AttributionKind::iter().zip((ROWS_TOP..).step_by(ROW_HEIGHT as usize)) {
+        let (rank, value) = summary.entry(kind).map_or_else(
+            || ("-".to_string(), "-".to_string()),
+            |entry| {
+                (
+                    format!("#{}", entry.rank),
+                    format_value(entry.unit, entry.value, locale),
+                )
+            },
+        );
+        let _ = write!(
+            svg,
+            r##"<text x="{PADDING}" y="{y}" font-size="15" fill="#a0a8b8">{}</text><text x="260" y="{y}" font-size="15" font-weight="bold" fill="#ffd54f" text-anchor="end">{rank}</text><text x="{right}" y="{y}" font-size="15" fill="#ffffff" text-anchor="end">{value}</text>"##,
+            kind_label(kind, locale),
+        );
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+#[utoipa::path(
+    get,
+    path = "/player-ranks/{uuid}/card",
+    params(
+        ("uuid" = Uuid, Path, description = "プレーヤーの UUID"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "集計期間。既定値は `all`"),
+        ("format" = Option<String>, Query, description = "`svg` または `png` (サーバーが対応している場合のみ)。既定値は `svg`"),
+        ("Accept-Language" = Option<String>, Header, description = "`en` を含む場合は英語で、それ以外は日本語で描く"),
+    ),
+    responses(
+        (status = 200, content_type = "image/svg+xml", description = "プレーヤーのカード"),
+        (status = 400, description = "クエリパラメータが不正"),
+        (status = 404, description = "プレーヤーがどのランキングにも含まれていない、または PNG に対応していない"),
+    )
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/player-ranks/{uuid}/card")]
+pub async fn player_rank_card(
+    req: HttpRequest,
+    path: Path<Uuid>,
+    data: web::Data<&'static AppState>,
+    skin_store: web::Data<SkinStore>,
+    rasterizer: web::Data<Rasterizer>,
+) -> impl Responder {
+    let qs: QString = req.query_string().into();
+
+    let time_range_specifier = time_range_from_qs(&qs);
+    let time_range = match AggregationTimeRange::from_str(time_range_specifier) {
+        Ok(r) => r,
+        Err(_) => return duration_not_recognized_response(time_range_specifier),
+    };
+
+    let format_specifier = qs.get("format").unwrap_or("svg");
+    let format = match ImageFormat::from_str(format_specifier) {
+        Ok(f) => f,
+        Err(_) => return image_format_not_recognized_response(format_specifier),
+    };
+
+    let locale = locale_from_request(&req).unwrap_or(Locale::Ja);
+    let player_uuid = path.into_inner();
+
+    let Some(summary) = data.player_summary(player_uuid, time_range).await else {
+        return HttpResponse::NotFound().body(format!(
+            "player with {player_uuid} is not in any ranking for time-range={time_range}"
+        ));
+    };
+
+    // スキンからプレーヤーがわかってしまうので、名前を公開しないプレーヤーの顔は描かない
+    let skin = if data.privacy_list.contains(player_uuid) {
+        None
+    } else {
+        match web::block(move || skin_store.skin(player_uuid)).await {
+            Ok(Ok(skin)) => skin,
+            Ok(Err(e)) => {
+                warn!("Error reading skin: {e:?}");
+                None
+            }
+            Err(e) => {
+                warn!("Error reading skin: {e}");
+                None
+            }
+        }
+    };
+
+    let svg = render_card(
+        &summary,
+        &data.privacy_list,
+        time_range,
+        skin.as_deref(),
+        locale,
+    );
+    let cache_control = CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(CARD_MAX_AGE_SECS),
+    ]);
+    let mut response = image_response(&rasterizer, format, svg, cache_control).await;
+    response.headers_mut().insert(
+        header::VARY,
+        header::HeaderValue::from_static("Accept-Language"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::{AppState, PlayerSummary, PlayerSummaryEntry, PrivacyList};
+    use crate::config::ImageConfig;
+    use crate::handlers::card::{player_rank_card, render_card};
+    use crate::handlers::formatting::Locale;
+    use crate::handlers::images::Rasterizer;
+    use crate::models::{AggregationTimeRange, AttributionKind, AttributionUnit, Player};
+    use crate::skin_store::SkinStore;
+    use crate::test_fixtures::{player, records_with_values};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::web::Data;
+    use actix_web::App;
+    use uuid::Uuid;
+
+    #[test]
+    fn render_ranks_and_values_of_every_kind() {
+        let summary = PlayerSummary {
+            player: Player {
+                name: "a<b".to_string(),
+                ..player(1)
+            },
+            entries: vec![
+                PlayerSummaryEntry {
+                    kind: AttributionKind::Break,
+                    unit: AttributionUnit::Count,
+                    rank: 12,
+                    value: 1_234_567,
+                },
+                PlayerSummaryEntry {
+                    kind: AttributionKind::PlayTicks,
+                    unit: AttributionUnit::Tick,
+                    rank: 3,
+                    value: 2 * 60 * 60 * 20,
+                },
+            ],
+        };
+
+        let svg = render_card(
+            &summary,
+            &PrivacyList::default(),
+            AggregationTimeRange::LastOneWeek,
+            None,
+            Locale::En,
+        );
+        assert!(svg.starts_with("<svg "), "{svg}");
+        assert!(svg.ends_with("</svg>"), "{svg}");
+        assert!(svg.contains(">a&lt;b</text>"), "{svg}");
+        assert!(svg.contains(">Past week</text>"), "{svg}");
+        for expected in [">#12<", ">1,234,567<", ">#3<", ">2 h 0 min<", ">Votes<"] {
+            assert!(svg.contains(expected), "{expected} is not in {svg}");
+        }
+
+        #[cfg(feature = "png")]
+        {
+            use crate::handlers::images::svg_to_png;
+            use std::sync::Arc;
+
+            let png = svg_to_png(&svg, Arc::default()).unwrap();
+            assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn card_varies_by_accept_language() {
+        let state: &'static AppState = Box::leak(Box::default());
+        state
+            .break_count_rankings
+            .for_time_range(AggregationTimeRange::All)
+            .write()
+            .await
+            .hydrate_record_set(records_with_values(&[10]));
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(SkinStore::new(None)))
+                .app_data(Data::new(Rasterizer::new(&ImageConfig::default())))
+                .service(player_rank_card),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri(&format!("/player-ranks/{}/card", Uuid::from_u128(1)))
+            .insert_header((header::ACCEPT_LANGUAGE, "en"))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=60"
+        );
+        assert_eq!(
+            response.headers().get(header::VARY).unwrap(),
+            "Accept-Language"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::app_models::PrivacyList;
+use crate::handlers::formatting::{
+    format_value, kind_label, ranking_title, time_range_label, Locale,
+};
+use crate::handlers::presentation_models::displayed_player_name;
+use crate::models::{
+    AggregatedPlayerAttribution, AggregationTimeRange, Player, RankedAttributionRecord,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// 一つの埋め込みに入れられるフィールドの最大数
+pub(crate) const MAX_EMBED_FIELDS: usize = 25;
+
+/// 埋め込みの左端に付く色
+const EMBED_COLOR: u32 = 0x4c_af_50;
+
+/// そのまま Discord に投稿できるメッセージ
+#[derive(Serialize)]
+pub(crate) struct DiscordMessage {
+    embeds: Vec<Embed>,
+}
+
+#[derive(Serialize)]
+struct Embed {
+    title: String,
+    color: u32,
+    fields: Vec<EmbedField>,
+}
+
+#[derive(Serialize)]
+struct EmbedField {
+    name: String,
+    value: String,
+    inline: bool,
+}
+
+/// 閲覧者のタイムゾーンで相対的に表示される、 Discord のタイムスタンプ記法
+fn discord_timestamp(at: DateTime<Utc>) -> String {
+    format!("<t:{}:R>", at.timestamp())
+}
+
+/// 最終ログアウトを隠すべきプレーヤーの場合は `None` を返す。
+fn displayed_last_quit(player: &Player, privacy_list: &PrivacyList) -> Option<String> {
+    (!privacy_list.contains(player.uuid)).then(|| discord_timestamp(player.last_quit))
+}
+
+/// プレーヤー名の `_` などが Markdown として解釈されないようにする。
+fn escape_markdown(text: &str) -> String {
+    text.chars()
+        .flat_map(|c| {
+            let escape = matches!(c, '\\' | '_' | '*' | '~' | '`' | '|' | '>');
+            escape.then_some('\\').into_iter().chain([c])
+        })
+        .collect()
+}
+
+/// ランキングの一部を、レコードごとに一つのフィールドを持つ埋め込みにする。
+///
+/// 埋め込みのフィールドの数には上限があるので、 `records` は [`MAX_EMBED_FIELDS`] 件までにする必要がある。
+pub(crate) fn ranking_to_discord_message<Attribution: AggregatedPlayerAttribution>(
+    records: &[RankedAttributionRecord<Attribution>],
+    time_range: AggregationTimeRange,
+    privacy_list: &PrivacyList,
+) -> DiscordMessage {
+    let kind = Attribution::KIND;
+
+    DiscordMessage {
+        embeds: vec![Embed {
+            title: ranking_title(kind, time_range, Locale::Ja),
+            color: EMBED_COLOR,
+            fields: records
+                .iter()
+                .map(|record| {
+                    let attribution_record = &record.attribution_record;
+                    let player = &attribution_record.player;
+                    let value = format_value(
+                        Attribution::UNIT,
+                        attribution_record.attribution.raw_u64_data(),
+                        Locale::Ja,
+                    );
+                    EmbedField {
+                        name: format!(
+                            "#{} {}",
+                            record.rank,
+                            escape_markdown(displayed_player_name(player, privacy_list))
+                        ),
+                        value: match displayed_last_quit(player, privacy_list) {
+                            Some(last_quit) => format!("{value}\n最終ログアウト {last_quit}"),
+                            None => value,
+                        },
+                        inline: false,
+                    }
+                })
+                .collect(),
+        }],
+    }
+}
+
+/// プレーヤーの順位を、順位と値と最終ログアウトを並べた埋め込みにする。
+pub(crate) fn player_rank_to_discord_message<Attribution: AggregatedPlayerAttribution>(
+    record: &RankedAttributionRecord<Attribution>,
+    time_range: AggregationTimeRange,
+    privacy_list: &PrivacyList,
+) -> DiscordMessage {
+    let kind = Attribution::KIND;
+    let attribution_record = &record.attribution_record;
+    let field = |name: &str, value: String| EmbedField {
+        name: name.to_string(),
+        value,
+        inline: true,
+    };
+
+    DiscordMessage {
+        embeds: vec![Embed {
+            title: format!(
+                "{} の{}ランキング ({})",
+                escape_markdown(displayed_player_name(
+                    &attribution_record.player,
+                    privacy_list
+                )),
+                kind_label(kind, Locale::Ja),
+                time_range_label(time_range, Locale::Ja)
+            ),
+            color: EMBED_COLOR,
+            fields: vec![
+                field("順位", format!("#{}", record.rank)),
+                field(
+                    kind_label(kind, Locale::Ja),
+                    format_value(
+                        Attribution::UNIT,
+                        attribution_record.attribution.raw_u64_data(),
+                        Locale::Ja,
+                    ),
+                ),
+                field(
+                    "最終ログアウト",
+                    displayed_last_quit(&attribution_record.player, privacy_list)
+                        .unwrap_or_else(|| "非公開".to_string()),
+                ),
+            ],
+        }],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::PrivacyList;
+    use crate::handlers::discord::{player_rank_to_discord_message, ranking_to_discord_message};
+    use crate::models::{
+        AggregatedPlayerAttribution, AggregationTimeRange, AttributionRecord, BreakCount,
+        PlayTicks, Player, RankedAttributionRecord,
+    };
+    use crate::test_fixtures::player;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn ranked_record<Attribution: AggregatedPlayerAttribution>(
+        rank: u32,
+        name: &str,
+        value: u64,
+    ) -> RankedAttributionRecord<Attribution> {
+        RankedAttributionRecord {
+            rank,
+            attribution_record: AttributionRecord {
+                player: Player {
+                    name: name.to_string(),
+                    ..player(rank.into())
+                },
+                attribution: Attribution::from_raw_u64_data(value),
+            },
+            previous: None,
+        }
+    }
+
+    #[test]
+    fn format_values_and_timestamps_for_discord() {
+        let records = [
+            ranked_record::<BreakCount>(1, "some_player", 1_234_567),
+            ranked_record::<BreakCount>(2, "player2", 999),
+        ];
+        let privacy_list = PrivacyList::default();
+        assert_eq!(
+            json!(ranking_to_discord_message(
+                &records,
+                AggregationTimeRange::LastOneMonth,
+                &privacy_list
+            )),
+            json!({
+                "embeds": [{
+                    "title": "整地量ランキング (月間)",
+                    "color": 0x4c_af_50,
+                    "fields": [
+                        {
+                            "name": "#1 some\\_player",
+                            "value": "1,234,567\n最終ログアウト <t:1767225600:R>",
+                            "inline": false,
+                        },
+                        {
+                            "name": "#2 player2",
+                            "value": "999\n最終ログアウト <t:1767225600:R>",
+                            "inline": false,
+                        },
+                    ],
+                }],
+            })
+        );
+
+        // 1234 時間 56 分 7 秒
+        let play_ticks = ((1234 * 60 + 56) * 60 + 7) * 20;
+        assert_eq!(
+            json!(player_rank_to_discord_message(
+                &ranked_record::<PlayTicks>(3, "player3", play_ticks),
+                AggregationTimeRange::All,
+                &privacy_list
+            ))["embeds"][0]["fields"][1],
+            json!({ "name": "プレイ時間", "value": "1234時間56分", "inline": true })
+        );
+
+        // 名前を公開しないプレーヤーは、名前が匿名のラベルになり、最終ログアウトが隠される
+        privacy_list.insert(Uuid::from_u128(2));
+        assert_eq!(
+            json!(ranking_to_discord_message(
+                &records,
+                AggregationTimeRange::LastOneMonth,
+                &privacy_list
+            ))["embeds"][0]["fields"][1],
+            json!({ "name": "#2 匿名のプレーヤー", "value": "999", "inline": false })
+        );
+    }
+}
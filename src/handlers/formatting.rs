@@ -0,0 +1,139 @@
+use crate::models::{AggregationTimeRange, AttributionKind, AttributionUnit};
+use actix_web::http::header::{AcceptLanguage, Preference};
+use actix_web::{HttpMessage, HttpRequest};
+
+/// 値を人が読みやすい形にするときの言語
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Locale {
+    Ja,
+    En,
+}
+
+/// `Accept-Language` ヘッダーで最も優先されている、対応している言語。
+/// ヘッダーがない場合や、対応している言語が含まれていない場合は `None` を返す。
+pub(crate) fn locale_from_request(req: &HttpRequest) -> Option<Locale> {
+    req.get_header::<AcceptLanguage>()?
+        .ranked()
+        .into_iter()
+        .find_map(|preference| match preference {
+            Preference::Specific(tag) => match tag.primary_language() {
+                "ja" => Some(Locale::Ja),
+                "en" => Some(Locale::En),
+                _ => None,
+            },
+            Preference::Any => None,
+        })
+}
+
+pub(crate) fn kind_label(kind: AttributionKind, locale: Locale) -> &'static str {
+    match (kind, locale) {
+        (AttributionKind::Break, Locale::Ja) => "整地量",
+        (AttributionKind::Break, Locale::En) => "Blocks broken",
+        (AttributionKind::Build, Locale::Ja) => "建築量",
+        (AttributionKind::Build, Locale::En) => "Blocks built",
+        (AttributionKind::PlayTicks, Locale::Ja) => "プレイ時間",
+        (AttributionKind::PlayTicks, Locale::En) => "Play time",
+        (AttributionKind::VoteCount, Locale::Ja) => "投票数",
+        (AttributionKind::VoteCount, Locale::En) => "Votes",
+    }
+}
+
+pub(crate) fn time_range_label(time_range: AggregationTimeRange, locale: Locale) -> &'static str {
+    match (time_range, locale) {
+        (AggregationTimeRange::All, Locale::Ja) => "累計",
+        (AggregationTimeRange::All, Locale::En) => "All time",
+        (AggregationTimeRange::LastOneYear, Locale::Ja) => "年間",
+        (AggregationTimeRange::LastOneYear, Locale::En) => "Past year",
+        (AggregationTimeRange::LastOneMonth, Locale::Ja) => "月間",
+        (AggregationTimeRange::LastOneMonth, Locale::En) => "Past month",
+        (AggregationTimeRange::LastOneWeek, Locale::Ja) => "週間",
+        (AggregationTimeRange::LastOneWeek, Locale::En) => "Past week",
+        (AggregationTimeRange::LastOneDay, Locale::Ja) => "日間",
+        (AggregationTimeRange::LastOneDay, Locale::En) => "Past day",
+    }
+}
+
+/// `整地量ランキング (月間)` のような、ランキングの見出し
+pub(crate) fn ranking_title(
+    kind: AttributionKind,
+    time_range: AggregationTimeRange,
+    locale: Locale,
+) -> String {
+    let kind_label = kind_label(kind, locale);
+    let time_range_label = time_range_label(time_range, locale);
+    match locale {
+        Locale::Ja => format!("{kind_label}ランキング ({time_range_label})"),
+        Locale::En => format!("{kind_label} ranking ({time_range_label})"),
+    }
+}
+
+pub(crate) fn with_thousands_separators(n: u64) -> String {
+    let digits = n.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+/// 単位が `unit` の値 `raw` を、 `locale` で人が読みやすい形にする。
+///
+/// 回数は桁区切りを付け、 tick は桁区切りを付けない時間と分で表す (秒以下は切り捨てる)。
+pub(crate) fn format_value(unit: AttributionUnit, raw: u64, locale: Locale) -> String {
+    match unit {
+        AttributionUnit::Count => with_thousands_separators(raw),
+        AttributionUnit::Tick => {
+            let minutes = raw / AttributionUnit::TICKS_PER_SECOND / 60;
+            let hours = minutes / 60;
+            let minutes = minutes % 60;
+            match locale {
+                Locale::Ja => format!("{hours}時間{minutes}分"),
+                Locale::En => format!("{hours} h {minutes} min"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handlers::formatting::{format_value, locale_from_request, Locale};
+    use crate::models::AttributionUnit;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn format_values_in_preferred_locale() {
+        let locale = |accept_language: &str| {
+            locale_from_request(
+                &TestRequest::default()
+                    .insert_header(("accept-language", accept_language))
+                    .to_http_request(),
+            )
+        };
+        assert_eq!(locale("ja-JP,ja;q=0.9,en;q=0.8"), Some(Locale::Ja));
+        assert_eq!(locale("fr;q=0.9, en-US;q=0.5, ja;q=0.3"), Some(Locale::En));
+        assert_eq!(locale("fr, *"), None);
+        assert_eq!(
+            locale_from_request(&TestRequest::default().to_http_request()),
+            None
+        );
+
+        // 1234 時間 56 分 7 秒
+        let ticks = ((1234 * 60 + 56) * 60 + 7) * AttributionUnit::TICKS_PER_SECOND;
+        assert_eq!(
+            format_value(AttributionUnit::Tick, ticks, Locale::Ja),
+            "1234時間56分"
+        );
+        assert_eq!(
+            format_value(AttributionUnit::Tick, ticks, Locale::En),
+            "1234 h 56 min"
+        );
+        assert_eq!(
+            format_value(AttributionUnit::Count, 1_234_567, Locale::En),
+            "1,234,567"
+        );
+        assert_eq!(format_value(AttributionUnit::Count, 999, Locale::Ja), "999");
+    }
+}
@@ -0,0 +1,427 @@
+use crate::app_models::{AppState, PrivacyList};
+use crate::config::{GraphqlConfig, RankingsConfig};
+use crate::handlers::presentation_models::{
+    self, player_to_presentation_player, ranked_record_to_presentation_ranking_record,
+    RankingRecord,
+};
+use crate::models::{self, AggregatedPlayerAttribution, RankedAttributionRecord};
+use actix_web::{web, HttpResponse, Responder};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Enum, Object, Request, Schema, SimpleObject,
+};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+pub type RankingSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// `state` のランキングを読む GraphQL のスキーマを作る。
+pub fn build_schema(
+    state: &'static AppState,
+    rankings_config: web::Data<RankingsConfig>,
+    config: &GraphqlConfig,
+) -> RankingSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(state)
+        .data(rankings_config)
+        .limit_depth(config.max_depth())
+        .limit_complexity(config.max_complexity())
+        .finish()
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(name = "AttributionKind", remote = "models::AttributionKind")]
+enum AttributionKind {
+    Break,
+    Build,
+    PlayTicks,
+    VoteCount,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(name = "TimeRange", remote = "models::AggregationTimeRange")]
+enum AggregationTimeRange {
+    All,
+    #[graphql(name = "YEAR")]
+    LastOneYear,
+    #[graphql(name = "MONTH")]
+    LastOneMonth,
+    #[graphql(name = "WEEK")]
+    LastOneWeek,
+    #[graphql(name = "DAY")]
+    LastOneDay,
+}
+
+/// `$kind` の `$time_range` のランキングを読み取りロックした上で `$ranking` に束縛し、 `$body` を評価する。
+macro_rules! with_ranking {
+    ($ctx:expr, $kind:expr, $time_range:expr, |$ranking:ident| $body:expr) => {{
+        let state = $ctx.data::<&'static AppState>()?;
+        let time_range = models::AggregationTimeRange::from($time_range);
+
+        match models::AttributionKind::from($kind) {
+            models::AttributionKind::Break => {
+                let $ranking = state
+                    .break_count_rankings
+                    .for_time_range(time_range)
+                    .read()
+                    .await;
+                $body
+            }
+            models::AttributionKind::Build => {
+                let $ranking = state
+                    .build_count_rankings
+                    .for_time_range(time_range)
+                    .read()
+                    .await;
+                $body
+            }
+            models::AttributionKind::PlayTicks => {
+                let $ranking = state
+                    .play_ticks_rankings
+                    .for_time_range(time_range)
+                    .read()
+                    .await;
+                $body
+            }
+            models::AttributionKind::VoteCount => {
+                let $ranking = state
+                    .vote_count_rankings
+                    .for_time_range(time_range)
+                    .read()
+                    .await;
+                $body
+            }
+        }
+    }};
+}
+
+fn check_limit(
+    ctx: &Context<'_>,
+    kind: AttributionKind,
+    limit: usize,
+) -> async_graphql::Result<()> {
+    let max_limit = ctx
+        .data::<web::Data<RankingsConfig>>()?
+        .for_kind(kind.into())
+        .max_limit();
+
+    if limit > max_limit {
+        return Err(format!("{limit} is too large for a limit").into());
+    }
+    Ok(())
+}
+
+pub struct Player {
+    /// 順位を調べるための元の UUID。名前を公開しないプレーヤーについても、レスポンスには含めない。
+    uuid: Uuid,
+    presentation: presentation_models::Player,
+}
+
+impl Player {
+    fn new(player: &models::Player, privacy_list: &PrivacyList) -> Self {
+        Self {
+            uuid: player.uuid,
+            presentation: player_to_presentation_player(player, privacy_list),
+        }
+    }
+}
+
+#[Object]
+impl Player {
+    /// 名前を公開しないプレーヤーについては仮の UUID
+    async fn uuid(&self) -> Uuid {
+        self.presentation.uuid
+    }
+
+    async fn name(&self) -> &str {
+        &self.presentation.name
+    }
+
+    /// 名前を公開しないプレーヤーについては `1970-01-01T00:00:00Z`
+    async fn last_quit(&self) -> DateTime<Utc> {
+        self.presentation.last_quit
+    }
+
+    /// このプレーヤーの順位。ランキングに含まれていない場合は `null`
+    async fn rank(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "type", default_with = "AttributionKind::Break")] kind: AttributionKind,
+        #[graphql(default_with = "AggregationTimeRange::All")] time_range: AggregationTimeRange,
+    ) -> async_graphql::Result<Option<RankingRecord>> {
+        Ok(with_ranking!(ctx, kind, time_range, |ranking| ranking
+            .record_with_uuid(self.uuid)
+            .map(|record| {
+                ranked_record_to_presentation_ranking_record(&record)
+            })))
+    }
+
+    /// ランキング上でこのプレーヤーのすぐ上と下にいる、それぞれ `count` 人までのプレーヤー
+    #[graphql(complexity = "count * 2 * child_complexity")]
+    async fn neighbours(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "type", default_with = "AttributionKind::Break")] kind: AttributionKind,
+        #[graphql(default_with = "AggregationTimeRange::All")] time_range: AggregationTimeRange,
+        #[graphql(default = 2)] count: usize,
+    ) -> async_graphql::Result<Vec<PlayerRankingRecord>> {
+        check_limit(ctx, kind, count.saturating_mul(2))?;
+        let privacy_list = &ctx.data::<&'static AppState>()?.privacy_list;
+
+        Ok(with_ranking!(ctx, kind, time_range, |ranking| {
+            let records = ranking.ranked_records();
+            records
+                .iter()
+                .position(|r| r.attribution_record.player.uuid == self.uuid)
+                .map(|index| {
+                    let start = index.saturating_sub(count);
+                    let end = records.len().min(index.saturating_add(count + 1));
+                    records[start..end]
+                        .iter()
+                        .filter(|r| r.attribution_record.player.uuid != self.uuid)
+                        .map(|r| ranked_record_to_player_ranking_record(r, privacy_list))
+                        .collect()
+                })
+                .unwrap_or_default()
+        }))
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct PlayerRankingRecord {
+    player: Player,
+    record: RankingRecord,
+}
+
+fn ranked_record_to_player_ranking_record<Attribution: AggregatedPlayerAttribution>(
+    ranked_record: &RankedAttributionRecord<Attribution>,
+    privacy_list: &PrivacyList,
+) -> PlayerRankingRecord {
+    PlayerRankingRecord {
+        player: Player::new(&ranked_record.attribution_record.player, privacy_list),
+        record: ranked_record_to_presentation_ranking_record(ranked_record),
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct RankingPage {
+    /// ランキングに含まれるプレーヤーの総数
+    total_count: usize,
+    records: Vec<PlayerRankingRecord>,
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// ランキングのうち、先頭から `offset` 件を読み飛ばした `limit` 件
+    #[graphql(complexity = "limit * child_complexity")]
+    async fn ranking(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "type", default_with = "AttributionKind::Break")] kind: AttributionKind,
+        #[graphql(default_with = "AggregationTimeRange::All")] time_range: AggregationTimeRange,
+        #[graphql(default = 0)] offset: usize,
+        #[graphql(default = 20)] limit: usize,
+    ) -> async_graphql::Result<RankingPage> {
+        check_limit(ctx, kind, limit)?;
+        let privacy_list = &ctx.data::<&'static AppState>()?.privacy_list;
+
+        Ok(with_ranking!(ctx, kind, time_range, |ranking| {
+            RankingPage {
+                total_count: ranking.ranked_records().len(),
+                records: ranking
+                    .paginate(offset, limit)
+                    .0
+                    .iter()
+                    .map(|r| ranked_record_to_player_ranking_record(r, privacy_list))
+                    .collect(),
+            }
+        }))
+    }
+
+    /// `uuid` のプレーヤー。どのランキングにも含まれていない場合は `null`
+    async fn player(&self, ctx: &Context<'_>, uuid: Uuid) -> async_graphql::Result<Option<Player>> {
+        let state = ctx.data::<&'static AppState>()?;
+
+        Ok(state
+            .player_summary(uuid, models::AggregationTimeRange::All)
+            .await
+            .map(|summary| Player::new(&summary.player, &state.privacy_list)))
+    }
+}
+
+/// ランキングとプレーヤーを GraphQL で取得する。
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    request_body(content = Object, description = "GraphQL のリクエスト (`query` と、任意で `variables` と `operationName`)"),
+    responses(
+        (status = 200, body = Object, description = "GraphQL のレスポンス (`data` と `errors`)"),
+    )
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::post("/graphql")]
+pub async fn graphql(
+    schema: web::Data<RankingSchema>,
+    request: web::Json<Request>,
+) -> impl Responder {
+    HttpResponse::Ok().json(schema.execute(request.into_inner()).await)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::AppState;
+    use crate::config::{GraphqlConfig, RankingsConfig};
+    use crate::handlers::graphql::build_schema;
+    use crate::models::AggregationTimeRange;
+    use crate::test_fixtures::records_of;
+    use actix_web::web::Data;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[actix_web::test]
+    async fn resolve_player_with_rank_and_neighbours() {
+        let state: &'static AppState = Box::leak(Box::default());
+        state
+            .break_count_rankings
+            .for_time_range(AggregationTimeRange::All)
+            .write()
+            .await
+            .hydrate_record_set(records_of(
+                (1..=5).map(|i| (i, 100 - u64::try_from(i).unwrap())),
+            ));
+        state.privacy_list.insert(Uuid::from_u128(2));
+        let schema = build_schema(
+            state,
+            Data::new(RankingsConfig::default()),
+            &GraphqlConfig::default(),
+        );
+
+        let response = schema
+            .execute(
+                r#"{
+                    ranking(limit: 2, offset: 1) { totalCount records { player { name } record { rankPosition } } }
+                    player(uuid: "00000000-0000-0000-0000-000000000003") {
+                        name
+                        all: rank { rankPosition value }
+                        week: rank(timeRange: WEEK) { rankPosition }
+                        neighbours(count: 1) { player { name } }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "ranking": {
+                    "totalCount": 5,
+                    "records": [
+                        { "player": { "name": "匿名のプレーヤー" }, "record": { "rankPosition": 2 } },
+                        { "player": { "name": "player3" }, "record": { "rankPosition": 3 } },
+                    ],
+                },
+                "player": {
+                    "name": "player3",
+                    "all": { "rankPosition": 3, "value": 97 },
+                    "week": null,
+                    "neighbours": [
+                        { "player": { "name": "匿名のプレーヤー" } },
+                        { "player": { "name": "player4" } },
+                    ],
+                },
+            })
+        );
+
+        let limited_schema = build_schema(
+            state,
+            Data::new(RankingsConfig::default()),
+            &GraphqlConfig {
+                max_depth: Some(4),
+                max_complexity: Some(100),
+            },
+        );
+        let limited_schema = &limited_schema;
+        let errors = |query: &'static str| async move {
+            let response = limited_schema.execute(query).await;
+            response
+                .errors
+                .into_iter()
+                .map(|e| e.message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            errors("{ ranking(limit: 5) { records { player { name } } } }").await,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            errors("{ ranking(limit: 50) { records { player { name } } } }").await,
+            vec!["Query is too complex.".to_string()]
+        );
+        assert_eq!(
+            errors(
+                "{ ranking(limit: 1) { records { player { neighbours { player { name } } } } } }"
+            )
+            .await,
+            vec!["Query is nested too deep.".to_string()]
+        );
+    }
+
+    #[actix_web::test]
+    async fn resolve_rank_and_neighbours_of_private_player() {
+        let state: &'static AppState = Box::leak(Box::default());
+        state
+            .break_count_rankings
+            .for_time_range(AggregationTimeRange::All)
+            .write()
+            .await
+            .hydrate_record_set(records_of(
+                (1..=3).map(|i| (i, 100 - u64::try_from(i).unwrap())),
+            ));
+        state.privacy_list.insert(Uuid::from_u128(2));
+        let pseudonym = state.privacy_list.pseudonym(Uuid::from_u128(2));
+        let schema = build_schema(
+            state,
+            Data::new(RankingsConfig::default()),
+            &GraphqlConfig::default(),
+        );
+
+        let response = schema
+            .execute(
+                r#"{
+                    player(uuid: "00000000-0000-0000-0000-000000000002") {
+                        uuid
+                        name
+                        lastQuit
+                        rank { rankPosition value }
+                        neighbours(count: 1) { player { uuid name } }
+                    }
+                    ranking(limit: 3) { records { player { uuid neighbours(count: 1) { player { name } } } } }
+                }"#,
+            )
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "player": {
+                    "uuid": pseudonym.to_string(),
+                    "name": "匿名のプレーヤー",
+                    "lastQuit": "1970-01-01T00:00:00+00:00",
+                    "rank": { "rankPosition": 2, "value": 98 },
+                    "neighbours": [
+                        { "player": { "uuid": Uuid::from_u128(1).to_string(), "name": "player1" } },
+                        { "player": { "uuid": Uuid::from_u128(3).to_string(), "name": "player3" } },
+                    ],
+                },
+                "ranking": {
+                    "records": [
+                        { "player": { "uuid": Uuid::from_u128(1).to_string(), "neighbours": [{ "player": { "name": "匿名のプレーヤー" } }] } },
+                        { "player": { "uuid": pseudonym.to_string(), "neighbours": [{ "player": { "name": "player1" } }, { "player": { "name": "player3" } }] } },
+                        { "player": { "uuid": Uuid::from_u128(3).to_string(), "neighbours": [{ "player": { "name": "匿名のプレーヤー" } }] } },
+                    ],
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use crate::handlers::presentation_models::player_snapshot_record_to_presentation_rank_history_point;
+#[allow(unused_imports)] // `utoipa::path` の中でのみ使われる
+use crate::handlers::presentation_models::RankHistoryPoint;
+use crate::handlers::ranking::{
+    duration_not_recognized_response, snapshots_disabled_response, time_not_recognized_response,
+    time_range_from_qs, unknown_attribution_kind,
+};
+use crate::models::{
+    AggregationTimeRange, AttributionKind, BreakCount, BuildCount, PlayTicks, VoteCount,
+};
+use crate::snapshot_store::{
+    parse_snapshot_time, parse_window_start, HistoryResolution, RankingSnapshotStore,
+};
+use actix_web::body::BoxBody;
+use actix_web::web::Path;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use log::error;
+use qstring::QString;
+use std::str::FromStr;
+use uuid::Uuid;
+
+fn resolution_not_recognized_response(resolution_str: &str) -> HttpResponse<BoxBody> {
+    HttpResponse::BadRequest().body(format!(
+        "{resolution_str} is not a recognized resolution specifier."
+    ))
+}
+
+const DEFAULT_HISTORY_WINDOW_DAYS: i64 = 30;
+
+#[utoipa::path(
+    get,
+    path = "/player-ranks/{uuid}/history",
+    params(
+        ("uuid" = Uuid, Path, description = "プレーヤーの UUID"),
+        ("type" = Option<AttributionKind>, Query, description = "ランキングの種類。既定値は `break`"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "集計期間。既定値は `all`"),
+        ("from" = Option<String>, Query, description = "期間の始まり。既定値は `to` の30日前"),
+        ("to" = Option<String>, Query, description = "期間の終わり。既定値は現在"),
+        ("resolution" = Option<String>, Query, description = "集約の粒度 (`hourly`, `daily`, `weekly`)。既定値は `daily`"),
+    ),
+    responses(
+        (status = 200, body = [RankHistoryPoint]),
+        (status = 400, description = "クエリパラメータが不正"),
+        (status = 404, description = "スナップショットが保存されていない"),
+    )
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/player-ranks/{uuid}/history")]
+pub async fn player_rank_history(
+    req: HttpRequest,
+    path: Path<Uuid>,
+    snapshot_store: Option<web::Data<RankingSnapshotStore>>,
+) -> impl Responder {
+    let Some(store) = snapshot_store else {
+        return snapshots_disabled_response();
+    };
+
+    let qs: QString = req.query_string().into();
+
+    let time_range_specifier = time_range_from_qs(&qs);
+    let time_range = match AggregationTimeRange::from_str(time_range_specifier) {
+        Ok(r) => r,
+        Err(_) => return duration_not_recognized_response(time_range_specifier),
+    };
+
+    let resolution_specifier = qs.get("resolution").unwrap_or("daily");
+    let resolution = match HistoryResolution::from_str(resolution_specifier) {
+        Ok(r) => r,
+        Err(_) => return resolution_not_recognized_response(resolution_specifier),
+    };
+
+    let to = match qs.get("to") {
+        None => Utc::now(),
+        Some(to_specifier) => match parse_snapshot_time(to_specifier) {
+            Some(to) => to,
+            None => return time_not_recognized_response(to_specifier),
+        },
+    };
+
+    let from = match qs.get("from") {
+        None => to - Duration::days(DEFAULT_HISTORY_WINDOW_DAYS),
+        Some(from_specifier) => match parse_window_start(from_specifier) {
+            Some(from) => from,
+            None => return time_not_recognized_response(from_specifier),
+        },
+    };
+
+    if from > to {
+        return HttpResponse::BadRequest().body("from must not be later than to");
+    }
+
+    let attribution_kind = qs.get("type").unwrap_or("break");
+
+    let player_uuid = path.into_inner();
+
+    macro_rules! respond_using {
+        ($attribution:ty) => {{
+            let history = web::block(move || {
+                store.player_history::<$attribution>(time_range, player_uuid, from, to)
+            })
+            .await;
+
+            let records = match history {
+                Ok(Ok(records)) => records,
+                Ok(Err(e)) => {
+                    error!("Error reading player rank history: {e}");
+                    return HttpResponse::InternalServerError().finish();
+                }
+                Err(e) => {
+                    error!("Error reading player rank history: {e}");
+                    return HttpResponse::InternalServerError().finish();
+                }
+            };
+
+            HttpResponse::Ok().json(
+                resolution
+                    .downsample(records)
+                    .iter()
+                    .map(player_snapshot_record_to_presentation_rank_history_point)
+                    .collect::<Vec<_>>(),
+            )
+        }};
+    }
+
+    match attribution_kind {
+        "break" => respond_using!(BreakCount),
+        "build" => respond_using!(BuildCount),
+        "play_ticks" => respond_using!(PlayTicks),
+        "vote_count" => respond_using!(VoteCount),
+        other => unknown_attribution_kind(other),
+    }
+}
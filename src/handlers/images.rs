@@ -0,0 +1,197 @@
+use crate::config::ImageConfig;
+use actix_web::body::BoxBody;
+use actix_web::http::header::CacheControl;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+#[cfg(feature = "png")]
+use log::error;
+#[cfg(feature = "png")]
+use resvg::{tiny_skia, usvg};
+#[cfg(feature = "png")]
+use std::sync::Arc;
+use strum::EnumString;
+
+/// 画像を返す形式
+#[derive(Clone, Copy, PartialEq, Eq, Hash, EnumString)]
+pub(crate) enum ImageFormat {
+    #[strum(serialize = "svg")]
+    Svg,
+    /// `png` フィーチャーを有効にしてビルドした場合のみ使える
+    #[strum(serialize = "png")]
+    Png,
+}
+
+pub(crate) fn image_format_not_recognized_response(format_str: &str) -> HttpResponse<BoxBody> {
+    HttpResponse::BadRequest().body(format!("{format_str} is not a recognized image format."))
+}
+
+/// SVG の `font-family`。 PNG に変換するサーバーに日本語のフォントがない場合は `sans-serif` にあたるフォントで描かれる。
+pub(crate) const FONT_FAMILY: &str = "'Noto Sans JP', 'Noto Sans CJK JP', sans-serif";
+
+/// SVG のテキストや属性値に埋め込めるよう、 `text` の特殊文字を実体参照にする。
+pub(crate) fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// スキン `skin` (64x64 の PNG) から顔の部分を切り出し、 `(x, y)` に一辺 `size` の大きさで描く SVG の要素。
+/// 帽子などの外側のレイヤーも重ねる。スキンがない場合は無地の四角形を描く。
+pub(crate) fn face_svg(skin: Option<&[u8]>, x: u32, y: u32, size: u32) -> String {
+    let Some(skin) = skin else {
+        return format!(
+            r##"<rect x="{x}" y="{y}" width="{size}" height="{size}" fill="#8d8d8d"/>"##
+        );
+    };
+
+    let href = format!("data:image/png;base64,{}", STANDARD.encode(skin));
+    // 入れ子の `svg` の `viewBox` で、スキンのうち顔 (8, 8) と外側のレイヤー (40, 8) の 8x8 の部分だけを表示する。
+    // resvg は `image-rendering: pixelated` を `style` 属性でしか受け付けない。
+    ["8 8 8 8", "40 8 8 8"]
+        .iter()
+        .map(|view_box| {
+            format!(
+                r#"<svg x="{x}" y="{y}" width="{size}" height="{size}" viewBox="{view_box}"><image width="64" height="64" style="image-rendering:pixelated" href="{href}"/></svg>"#
+            )
+        })
+        .collect()
+}
+
+/// SVG を PNG に変換するためのフォントを持つ。 `png` フィーチャーが無効な場合は何も持たず、変換もできない。
+pub struct Rasterizer {
+    #[cfg(feature = "png")]
+    fonts: Arc<usvg::fontdb::Database>,
+}
+
+impl Rasterizer {
+    #[cfg(feature = "png")]
+    pub fn new(config: &ImageConfig) -> Self {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        if let Some(font_dir) = &config.font_dir {
+            fonts.load_fonts_dir(font_dir);
+        }
+
+        // `sans-serif` は既定では Arial を指すので、 Arial がない環境では何も描かれなくなってしまう
+        let has_family = |family: &str| {
+            fonts
+                .faces()
+                .any(|face| face.families.iter().any(|(name, _)| name == family))
+        };
+        let sans_serif_family = config.font_family.clone().or_else(|| {
+            if has_family("Arial") {
+                return None;
+            }
+            let families = fonts
+                .faces()
+                .flat_map(|face| face.families.iter().map(|(name, _)| name.clone()))
+                .collect::<Vec<_>>();
+            families
+                .iter()
+                .find(|name| name.contains("Sans"))
+                .or(families.first())
+                .cloned()
+        });
+        if let Some(family) = sans_serif_family {
+            fonts.set_sans_serif_family(family);
+        }
+
+        Self {
+            fonts: Arc::new(fonts),
+        }
+    }
+
+    #[cfg(not(feature = "png"))]
+    pub fn new(_config: &ImageConfig) -> Self {
+        Self {}
+    }
+}
+
+#[cfg(feature = "png")]
+pub(crate) fn svg_to_png(svg: &str, fonts: Arc<usvg::fontdb::Database>) -> anyhow::Result<Vec<u8>> {
+    use anyhow::Context;
+
+    let options = usvg::Options {
+        fontdb: fonts,
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_str(svg, &options)?;
+    let size = tree.size().to_int_size();
+    let mut pixmap =
+        tiny_skia::Pixmap::new(size.width(), size.height()).context("image has no area")?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+
+    Ok(pixmap.encode_png()?)
+}
+
+/// `svg` を `format` の形式の画像のバイト列にする。
+/// 変換できない場合は、代わりに返すべきエラーのレスポンスを返す。
+pub(crate) async fn encode_image(
+    rasterizer: &web::Data<Rasterizer>,
+    format: ImageFormat,
+    svg: String,
+) -> Result<Bytes, HttpResponse<BoxBody>> {
+    match format {
+        ImageFormat::Svg => Ok(Bytes::from(svg)),
+        #[cfg(feature = "png")]
+        ImageFormat::Png => {
+            let fonts = rasterizer.fonts.clone();
+            match web::block(move || svg_to_png(&svg, fonts)).await {
+                Ok(Ok(png)) => Ok(Bytes::from(png)),
+                Ok(Err(e)) => {
+                    error!("Error rendering PNG image: {e:?}");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+                Err(e) => {
+                    error!("Error rendering PNG image: {e}");
+                    Err(HttpResponse::InternalServerError().finish())
+                }
+            }
+        }
+        #[cfg(not(feature = "png"))]
+        ImageFormat::Png => {
+            let _ = rasterizer;
+            Err(HttpResponse::NotFound().body("PNG images are not available on this server"))
+        }
+    }
+}
+
+/// [`encode_image`] で `format` の形式にした画像 `image` を返す。
+pub(crate) fn encoded_image_response(
+    format: ImageFormat,
+    image: Bytes,
+    cache_control: CacheControl,
+) -> HttpResponse<BoxBody> {
+    let content_type = match format {
+        ImageFormat::Svg => "image/svg+xml",
+        ImageFormat::Png => "image/png",
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(cache_control)
+        .body(image)
+}
+
+/// `svg` を `format` の形式の画像として返す。
+pub(crate) async fn image_response(
+    rasterizer: &web::Data<Rasterizer>,
+    format: ImageFormat,
+    svg: String,
+    cache_control: CacheControl,
+) -> HttpResponse<BoxBody> {
+    match encode_image(rasterizer, format, svg).await {
+        Ok(image) => encoded_image_response(format, image, cache_control),
+        Err(response) => response,
+    }
+}
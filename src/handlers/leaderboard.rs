@@ -0,0 +1,415 @@
+use crate::app_models::{AppState, LockedRankingsForTimeRanges, PrivacyList};
+use crate::handlers::formatting::{format_value, locale_from_request, ranking_title, Locale};
+use crate::handlers::images::{
+    encode_image, encoded_image_response, escape_xml, image_format_not_recognized_response,
+    ImageFormat, Rasterizer, FONT_FAMILY,
+};
+use crate::handlers::presentation_models::displayed_player_name;
+use crate::handlers::ranking::{
+    duration_not_recognized_response, time_range_from_qs, unknown_attribution_kind,
+};
+use crate::models::{AggregatedPlayerAttribution, AggregationTimeRange, AttributionKind};
+use actix_web::body::BoxBody;
+use actix_web::http::header::{self, CacheControl, CacheDirective};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use qstring::QString;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use strum::EnumString;
+
+/// 画像に載せる上位のプレーヤーの数
+const LEADERBOARD_ROWS: usize = 10;
+
+/// 以下の座標は、幅が `BASE_WIDTH` のときのもの。 [`LeaderboardSize`] に応じて全体を拡大・縮小する。
+const BASE_WIDTH: u32 = 480;
+const HEADER_HEIGHT: u32 = 52;
+const ROW_HEIGHT: u32 = 28;
+const BASE_HEIGHT: u32 = HEADER_HEIGHT + ROW_HEIGHT * LEADERBOARD_ROWS as u32 + 12;
+
+/// 画像を CDN やブラウザにキャッシュさせてよい時間 (秒)
+const LEADERBOARD_MAX_AGE_SECS: u32 = 60;
+
+/// 画像の配色
+#[derive(Clone, Copy, PartialEq, Eq, Hash, EnumString)]
+enum LeaderboardTheme {
+    #[strum(serialize = "dark")]
+    Dark,
+    #[strum(serialize = "light")]
+    Light,
+}
+
+struct ThemeColors {
+    background: &'static str,
+    border: &'static str,
+    text: &'static str,
+    muted: &'static str,
+    accent: &'static str,
+}
+
+impl LeaderboardTheme {
+    const fn colors(self) -> ThemeColors {
+        match self {
+            Self::Dark => ThemeColors {
+                background: "#1f2330",
+                border: "#2f3545",
+                text: "#ffffff",
+                muted: "#a0a8b8",
+                accent: "#ffd54f",
+            },
+            Self::Light => ThemeColors {
+                background: "#ffffff",
+                border: "#d0d7de",
+                text: "#1f2330",
+                muted: "#5f6b7a",
+                accent: "#b7791f",
+            },
+        }
+    }
+}
+
+/// 画像の大きさ
+#[derive(Clone, Copy, PartialEq, Eq, Hash, EnumString)]
+enum LeaderboardSize {
+    #[strum(serialize = "small")]
+    Small,
+    #[strum(serialize = "medium")]
+    Medium,
+    #[strum(serialize = "large")]
+    Large,
+}
+
+impl LeaderboardSize {
+    const fn width(self) -> u32 {
+        match self {
+            Self::Small => 320,
+            Self::Medium => BASE_WIDTH,
+            Self::Large => 720,
+        }
+    }
+}
+
+/// 画像の一行分。値は人が読みやすい形にしてある。
+struct LeaderboardRow {
+    rank: u32,
+    name: String,
+    value: String,
+}
+
+/// ランキングの上位 [`LEADERBOARD_ROWS`] 人を、ランキングの世代番号と共に取り出す。
+async fn top_rows<Attribution: AggregatedPlayerAttribution>(
+    rankings: &LockedRankingsForTimeRanges<Attribution>,
+    time_range: AggregationTimeRange,
+    privacy_list: &PrivacyList,
+    locale: Locale,
+) -> (u64, Vec<LeaderboardRow>) {
+    let ranking = rankings.for_time_range(time_range).read().await;
+    let rows = ranking
+        .paginate(0, LEADERBOARD_ROWS)
+        .0
+        .into_iter()
+        .map(|record| LeaderboardRow {
+            rank: record.rank,
+            name: displayed_player_name(&record.attribution_record.player, privacy_list)
+                .to_string(),
+            value: format_value(
+                Attribution::UNIT,
+                record.attribution_record.attribution.raw_u64_data(),
+                locale,
+            ),
+        })
+        .collect();
+
+    (ranking.generation(), rows)
+}
+
+/// 見出しと `rows` を並べたランキングの画像の SVG。
+fn render_leaderboard(
+    title: &str,
+    rows: &[LeaderboardRow],
+    theme: LeaderboardTheme,
+    size: LeaderboardSize,
+) -> String {
+    let colors = theme.colors();
+    let width = size.width();
+    let height = BASE_HEIGHT * width / BASE_WIDTH;
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {BASE_WIDTH} {BASE_HEIGHT}" font-family="{FONT_FAMILY}"><rect x="0.5" y="0.5" width="{}" height="{}" rx="12" fill="{}" stroke="{}"/>"#,
+        BASE_WIDTH - 1,
+        BASE_HEIGHT - 1,
+        colors.background,
+        colors.border
+    );
+    let _ = write!(
+        svg,
+        r#"<text x="24" y="34" font-size="18" font-weight="bold" fill="{}">{}</text><line x1="24" y1="{HEADER_HEIGHT}" x2="{}" y2="{HEADER_HEIGHT}" stroke="{}"/>"#,
+        colors.text,
+        escape_xml(title),
+        BASE_WIDTH - 24,
+        colors.border
+    );
+
+    for (row, y) in rows
+        .iter()
+        .zip((HEADER_HEIGHT + 22..).step_by(ROW_HEIGHT as usize))
+    {
+        let _ = write!(
+            svg,
+            r#"<text x="56" y="{y}" font-size="15" font-weight="bold" fill="{}" text-anchor="end">#{}</text><text x="68" y="{y}" font-size="15" fill="{}">{}</text><text x="{}" y="{y}" font-size="15" fill="{}" text-anchor="end">{}</text>"#,
+            colors.accent,
+            row.rank,
+            colors.text,
+            escape_xml(&row.name),
+            BASE_WIDTH - 24,
+            colors.muted,
+            row.value
+        );
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// 描いた画像を区別するためのキー。描いたときの [`ImageVersion`] はキャッシュの中身の方に持つ。
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct LeaderboardImageKey {
+    kind: AttributionKind,
+    time_range: AggregationTimeRange,
+    theme: LeaderboardTheme,
+    size: LeaderboardSize,
+    format: ImageFormat,
+    locale: Locale,
+}
+
+/// 画像を描いたときの、ランキングと名前を公開しないプレーヤーの一覧の世代番号
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct ImageVersion {
+    ranking: u64,
+    privacy_list: u64,
+}
+
+impl ImageVersion {
+    const fn is_older_than(self, other: Self) -> bool {
+        self.ranking < other.ranking || self.privacy_list < other.privacy_list
+    }
+}
+
+/// 描いたランキングの画像を、描いたときの [`ImageVersion`] と共に覚えておくキャッシュ。
+///
+/// ランキングか名前を公開しないプレーヤーの一覧が更新されるまでは、同じ画像を返して描き直さない。
+/// キーの組み合わせは有限なので、古くなった画像は捨てずに次に描いた画像で上書きする。
+#[derive(Default)]
+pub struct LeaderboardImageCache {
+    images: Mutex<HashMap<LeaderboardImageKey, (ImageVersion, Bytes)>>,
+}
+
+impl LeaderboardImageCache {
+    fn get(&self, key: &LeaderboardImageKey, version: ImageVersion) -> Option<Bytes> {
+        let images = self.images.lock().unwrap();
+        let (cached_version, image) = images.get(key)?;
+        (*cached_version == version).then(|| image.clone())
+    }
+
+    /// 描いている間に更新があり、より新しい画像が既に入っていた場合は何もしない。
+    fn insert(&self, key: LeaderboardImageKey, version: ImageVersion, image: Bytes) {
+        let mut images = self.images.lock().unwrap();
+        match images.get(&key) {
+            Some((cached_version, _)) if version.is_older_than(*cached_version) => {}
+            _ => {
+                images.insert(key, (version, image));
+            }
+        }
+    }
+}
+
+fn leaderboard_image_response(format: ImageFormat, image: Bytes) -> HttpResponse<BoxBody> {
+    let mut response = encoded_image_response(
+        format,
+        image,
+        CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(LEADERBOARD_MAX_AGE_SECS),
+        ]),
+    );
+    response.headers_mut().insert(
+        header::VARY,
+        header::HeaderValue::from_static("Accept-Language"),
+    );
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/ranking/image",
+    params(
+        ("type" = Option<AttributionKind>, Query, description = "ランキングの種類。既定値は `break`"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "集計期間。既定値は `all`"),
+        ("theme" = Option<String>, Query, description = "`dark` または `light`。既定値は `dark`"),
+        ("size" = Option<String>, Query, description = "`small` (幅 320)、 `medium` (幅 480) または `large` (幅 720)。既定値は `medium`"),
+        ("format" = Option<String>, Query, description = "`svg` または `png` (サーバーが対応している場合のみ)。既定値は `svg`"),
+        ("Accept-Language" = Option<String>, Header, description = "`en` を含む場合は英語で、それ以外は日本語で描く"),
+    ),
+    responses(
+        (status = 200, content_type = "image/svg+xml", description = "ランキングの上位 10 人の画像"),
+        (status = 400, description = "クエリパラメータが不正"),
+        (status = 404, description = "PNG に対応していない"),
+    )
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/ranking/image")]
+pub async fn leaderboard_image(
+    req: HttpRequest,
+    data: web::Data<&'static AppState>,
+    cache: web::Data<LeaderboardImageCache>,
+    rasterizer: web::Data<Rasterizer>,
+) -> impl Responder {
+    let qs: QString = req.query_string().into();
+
+    let time_range_specifier = time_range_from_qs(&qs);
+    let time_range = match AggregationTimeRange::from_str(time_range_specifier) {
+        Ok(r) => r,
+        Err(_) => return duration_not_recognized_response(time_range_specifier),
+    };
+
+    let attribution_kind = qs.get("type").unwrap_or("break");
+    let Ok(kind) = AttributionKind::from_str(attribution_kind) else {
+        return unknown_attribution_kind(attribution_kind);
+    };
+
+    let theme_specifier = qs.get("theme").unwrap_or("dark");
+    let Ok(theme) = LeaderboardTheme::from_str(theme_specifier) else {
+        return HttpResponse::BadRequest()
+            .body(format!("{theme_specifier} is not a recognized theme."));
+    };
+
+    let size_specifier = qs.get("size").unwrap_or("medium");
+    let Ok(size) = LeaderboardSize::from_str(size_specifier) else {
+        return HttpResponse::BadRequest()
+            .body(format!("{size_specifier} is not a recognized size."));
+    };
+
+    let format_specifier = qs.get("format").unwrap_or("svg");
+    let format = match ImageFormat::from_str(format_specifier) {
+        Ok(f) => f,
+        Err(_) => return image_format_not_recognized_response(format_specifier),
+    };
+
+    let locale = locale_from_request(&req).unwrap_or(Locale::Ja);
+
+    // 一覧を読んだ後に一覧が更新されても、古い世代番号で覚えられて次に描き直されるだけで済むよう、先に読む
+    let privacy_list = &data.privacy_list;
+    let privacy_list_generation = privacy_list.generation();
+    let (ranking_generation, rows) = match kind {
+        AttributionKind::Break => {
+            top_rows(&data.break_count_rankings, time_range, privacy_list, locale).await
+        }
+        AttributionKind::Build => {
+            top_rows(&data.build_count_rankings, time_range, privacy_list, locale).await
+        }
+        AttributionKind::PlayTicks => {
+            top_rows(&data.play_ticks_rankings, time_range, privacy_list, locale).await
+        }
+        AttributionKind::VoteCount => {
+            top_rows(&data.vote_count_rankings, time_range, privacy_list, locale).await
+        }
+    };
+    let version = ImageVersion {
+        ranking: ranking_generation,
+        privacy_list: privacy_list_generation,
+    };
+
+    let key = LeaderboardImageKey {
+        kind,
+        time_range,
+        theme,
+        size,
+        format,
+        locale,
+    };
+    if let Some(image) = cache.get(&key, version) {
+        return leaderboard_image_response(format, image);
+    }
+
+    let svg = render_leaderboard(&ranking_title(kind, time_range, locale), &rows, theme, size);
+    match encode_image(&rasterizer, format, svg).await {
+        Ok(image) => {
+            cache.insert(key, version, image.clone());
+            leaderboard_image_response(format, image)
+        }
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handlers::formatting::Locale;
+    use crate::handlers::images::ImageFormat;
+    use crate::handlers::leaderboard::{
+        render_leaderboard, ImageVersion, LeaderboardImageCache, LeaderboardImageKey,
+        LeaderboardRow, LeaderboardSize, LeaderboardTheme,
+    };
+    use crate::models::{AggregationTimeRange, AttributionKind};
+    use actix_web::web::Bytes;
+
+    #[test]
+    fn render_rows_in_given_size() {
+        let rows = (1..=3)
+            .map(|rank| LeaderboardRow {
+                rank,
+                name: format!("player<{rank}>"),
+                value: format!("{rank},000"),
+            })
+            .collect::<Vec<_>>();
+
+        let svg = render_leaderboard(
+            "整地量ランキング (週間)",
+            &rows,
+            LeaderboardTheme::Light,
+            LeaderboardSize::Small,
+        );
+        assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="320" height="229" viewBox="0 0 480 344""#), "{svg}");
+        assert!(svg.ends_with("</svg>"), "{svg}");
+        assert!(svg.contains(">整地量ランキング (週間)</text>"), "{svg}");
+        for expected in [">#1<", ">player&lt;1&gt;<", ">1,000<", ">#3<", ">3,000<"] {
+            assert!(svg.contains(expected), "{expected} is not in {svg}");
+        }
+        assert!(!svg.contains(">#4<"), "{svg}");
+    }
+
+    #[test]
+    fn cached_image_is_used_until_version_changes() {
+        let cache = LeaderboardImageCache::default();
+        let key = LeaderboardImageKey {
+            kind: AttributionKind::Break,
+            time_range: AggregationTimeRange::All,
+            theme: LeaderboardTheme::Dark,
+            size: LeaderboardSize::Medium,
+            format: ImageFormat::Svg,
+            locale: Locale::Ja,
+        };
+
+        let version = |ranking, privacy_list| ImageVersion {
+            ranking,
+            privacy_list,
+        };
+
+        cache.insert(key, version(1, 0), Bytes::from_static(b"first"));
+        assert_eq!(
+            cache.get(&key, version(1, 0)),
+            Some(Bytes::from_static(b"first"))
+        );
+        assert_eq!(cache.get(&key, version(2, 0)), None);
+        assert_eq!(cache.get(&key, version(1, 1)), None);
+
+        cache.insert(key, version(2, 0), Bytes::from_static(b"second"));
+        // 更新前のランキングから描いた画像が遅れて入ってきても、新しい画像は上書きされない
+        cache.insert(key, version(1, 0), Bytes::from_static(b"stale"));
+        assert_eq!(
+            cache.get(&key, version(2, 0)),
+            Some(Bytes::from_static(b"second"))
+        );
+    }
+}
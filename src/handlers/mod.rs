@@ -1,2 +1,37 @@
+pub mod admin;
+pub mod badge;
+pub mod card;
+pub mod discord;
+pub mod formatting;
+pub mod graphql;
+pub mod history;
+pub mod images;
+pub mod leaderboard;
+pub mod movers;
+pub mod openapi;
 pub mod presentation_models;
 pub mod ranking;
+pub mod updates;
+pub mod websocket;
+
+use actix_web::web::ServiceConfig;
+
+/// API のエンドポイントをすべて登録する。 `/v1` の下と、互換性のためにルートの下の両方に登録される。
+pub fn configure_api(config: &mut ServiceConfig) {
+    config
+        .service(ranking::ranking)
+        .service(leaderboard::leaderboard_image)
+        .service(ranking::player_rank)
+        .service(updates::ranking_stream)
+        .service(websocket::websocket)
+        .service(history::player_rank_history)
+        .service(card::player_rank_card)
+        .service(badge::player_rank_badge)
+        .service(movers::movers)
+        .service(graphql::graphql)
+        .service(admin::rehydrate)
+        .service(admin::metrics)
+        .service(admin::privacy_list)
+        .service(admin::anonymize_player)
+        .service(admin::unanonymize_player);
+}
@@ -0,0 +1,274 @@
+use crate::app_models::AppState;
+use crate::config::RankingsConfig;
+use crate::handlers::formatting::locale_from_request;
+use crate::handlers::presentation_models::ranked_record_to_presentation_player_ranking_record;
+#[allow(unused_imports)] // `utoipa::path` の中でのみ使われる
+use crate::handlers::presentation_models::PlayerRankingRecord;
+use crate::handlers::ranking::{
+    duration_not_recognized_response, parse_usize_param, snapshot_not_found,
+    snapshots_disabled_response, time_range_from_qs, unknown_attribution_kind,
+};
+use crate::models::{
+    AggregatedPlayerAttribution, AggregationTimeRange, AttributionKind, BreakCount, BuildCount,
+    MoverCriterion, PlayTicks, PreviousRankedRecord, VoteCount,
+};
+use crate::snapshot_store::RankingSnapshotStore;
+use actix_web::body::BoxBody;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use log::error;
+use qstring::QString;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use strum::EnumString;
+use uuid::Uuid;
+
+/// 何と比べた「伸び」を返すか
+#[derive(Clone, Copy, EnumString)]
+enum MoverWindow {
+    /// 前回のランキング更新時
+    #[strum(serialize = "previous")]
+    PreviousHydration,
+    /// 24時間前のスナップショット
+    #[strum(serialize = "day")]
+    LastOneDay,
+}
+
+fn criterion_not_recognized_response(criterion_str: &str) -> HttpResponse<BoxBody> {
+    HttpResponse::BadRequest().body(format!(
+        "{criterion_str} is not a recognized mover criterion."
+    ))
+}
+
+fn window_not_recognized_response(window_str: &str) -> HttpResponse<BoxBody> {
+    HttpResponse::BadRequest().body(format!("{window_str} is not a recognized mover window."))
+}
+
+/// 24時間前のスナップショットにおける、プレーヤーごとの順位と値
+type Baseline = HashMap<Uuid, (u32, u64)>;
+
+/// 比べるスナップショットを区別するためのキー
+type BaselineKey = (AttributionKind, AggregationTimeRange);
+
+/// `since=day` で比べる24時間前の順位と値を、読んだときのランキングの世代番号と共に覚えておくキャッシュ。
+///
+/// 比べる時刻がずれるのはランキングが更新されるまでの間だけなので、
+/// ランキングが更新されるまではスナップショットを読み直さない。
+#[derive(Default)]
+pub struct MoversBaselineCache {
+    baselines: Mutex<HashMap<BaselineKey, (u64, Arc<Baseline>)>>,
+}
+
+impl MoversBaselineCache {
+    fn get(
+        &self,
+        kind: AttributionKind,
+        time_range: AggregationTimeRange,
+        generation: u64,
+    ) -> Option<Arc<Baseline>> {
+        let baselines = self.baselines.lock().unwrap();
+        let (cached_generation, baseline) = baselines.get(&(kind, time_range))?;
+        (*cached_generation == generation).then(|| baseline.clone())
+    }
+
+    /// 読んでいる間にランキングが更新され、より新しいものが既に入っていた場合は何もしない。
+    fn insert(
+        &self,
+        kind: AttributionKind,
+        time_range: AggregationTimeRange,
+        generation: u64,
+        baseline: Arc<Baseline>,
+    ) {
+        let mut baselines = self.baselines.lock().unwrap();
+        match baselines.get(&(kind, time_range)) {
+            Some((cached_generation, _)) if generation < *cached_generation => {}
+            _ => {
+                baselines.insert((kind, time_range), (generation, baseline));
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/movers",
+    params(
+        ("type" = Option<AttributionKind>, Query, description = "ランキングの種類。既定値は `break`"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "集計期間。既定値は `all`"),
+        ("by" = Option<String>, Query, description = "順位 (`rank`) と値 (`value`) のどちらの伸びで並べるか。既定値は `rank`"),
+        ("since" = Option<String>, Query, description = "前回の更新 (`previous`) と24時間前 (`day`) のどちらと比べるか。既定値は `previous`"),
+        ("limit" = Option<usize>, Query, description = "取得するレコードの数。既定値は 10"),
+        ("Accept-Language" = Option<String>, Header, description = "`ja` か `en` を含む場合、その言語で人が読みやすい形にした値を `formatted_value` に含める"),
+    ),
+    responses(
+        (status = 200, body = [PlayerRankingRecord]),
+        (status = 400, description = "クエリパラメータが不正"),
+    )
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/movers")]
+pub async fn movers(
+    req: HttpRequest,
+    data: web::Data<&'static AppState>,
+    rankings_config: web::Data<RankingsConfig>,
+    baseline_cache: web::Data<MoversBaselineCache>,
+    snapshot_store: Option<web::Data<RankingSnapshotStore>>,
+) -> impl Responder {
+    let qs: QString = req.query_string().into();
+
+    let time_range_specifier = time_range_from_qs(&qs);
+    let time_range = match AggregationTimeRange::from_str(time_range_specifier) {
+        Ok(r) => r,
+        Err(_) => return duration_not_recognized_response(time_range_specifier),
+    };
+
+    let criterion_specifier = qs.get("by").unwrap_or("rank");
+    let criterion = match MoverCriterion::from_str(criterion_specifier) {
+        Ok(c) => c,
+        Err(_) => return criterion_not_recognized_response(criterion_specifier),
+    };
+
+    let window_specifier = qs.get("since").unwrap_or("previous");
+    let window = match MoverWindow::from_str(window_specifier) {
+        Ok(w) => w,
+        Err(_) => return window_not_recognized_response(window_specifier),
+    };
+
+    let limit = parse_usize_param(&qs, "limit").unwrap_or(10);
+
+    let attribution_kind = qs.get("type").unwrap_or("break");
+    let locale = locale_from_request(&req);
+
+    // 未知の種類はこの後で弾かれる
+    let max_limit = AttributionKind::from_str(attribution_kind).map_or(usize::MAX, |kind| {
+        rankings_config.for_kind(kind).max_limit()
+    });
+
+    if limit > max_limit {
+        return HttpResponse::BadRequest().body(format!("{limit} is too large for a limit"));
+    }
+
+    macro_rules! respond_using {
+        ($ranking:expr, $attribution:ty) => {{
+            let movers = match window {
+                MoverWindow::PreviousHydration => $ranking
+                    .for_time_range(time_range)
+                    .read()
+                    .await
+                    .top_movers(criterion, limit, |record| record.previous.clone()),
+                MoverWindow::LastOneDay => {
+                    let Some(store) = snapshot_store.clone() else {
+                        return snapshots_disabled_response();
+                    };
+
+                    let kind = <$attribution as AggregatedPlayerAttribution>::KIND;
+                    // スナップショットを読んだ後にランキングが更新されても、古い世代番号で覚えられるだけで済むよう、先に読む
+                    let generation = $ranking.for_time_range(time_range).read().await.generation();
+
+                    let baseline = match baseline_cache.get(kind, time_range, generation) {
+                        Some(baseline) => baseline,
+                        None => {
+                            let at = Utc::now() - Duration::days(1);
+                            let baseline_records =
+                                web::block(move || store.records_at::<$attribution>(time_range, at))
+                                    .await;
+
+                            let baseline_records = match baseline_records {
+                                Ok(Ok(Some(slice))) => slice.0,
+                                Ok(Ok(None)) => {
+                                    return snapshot_not_found(attribution_kind, time_range, at);
+                                }
+                                Ok(Err(e)) => {
+                                    error!("Error reading ranking snapshot: {e}");
+                                    return HttpResponse::InternalServerError().finish();
+                                }
+                                Err(e) => {
+                                    error!("Error reading ranking snapshot: {e}");
+                                    return HttpResponse::InternalServerError().finish();
+                                }
+                            };
+
+                            let baseline = Arc::new(
+                                baseline_records
+                                    .into_iter()
+                                    .map(|r| {
+                                        (
+                                            r.attribution_record.player.uuid,
+                                            (r.rank, r.attribution_record.attribution.raw_u64_data()),
+                                        )
+                                    })
+                                    .collect::<Baseline>(),
+                            );
+                            baseline_cache.insert(kind, time_range, generation, baseline.clone());
+                            baseline
+                        }
+                    };
+
+                    $ranking.for_time_range(time_range).read().await.top_movers(
+                        criterion,
+                        limit,
+                        |record| {
+                            baseline
+                                .get(&record.attribution_record.player.uuid)
+                                .map(|&(rank, value)| PreviousRankedRecord {
+                                    rank,
+                                    attribution: <$attribution>::from_raw_u64_data(value),
+                                })
+                        },
+                    )
+                }
+            };
+
+            HttpResponse::Ok()
+                .insert_header((header::VARY, "Accept-Language"))
+                .json(
+                    movers
+                        .iter()
+                        .map(|r| {
+                            ranked_record_to_presentation_player_ranking_record(
+                                r,
+                                &data.privacy_list,
+                            )
+                            .localized(locale)
+                        })
+                        .collect::<Vec<_>>(),
+                )
+        }};
+    }
+
+    match attribution_kind {
+        "break" => respond_using!(data.break_count_rankings, BreakCount),
+        "build" => respond_using!(data.build_count_rankings, BuildCount),
+        "play_ticks" => respond_using!(data.play_ticks_rankings, PlayTicks),
+        "vote_count" => respond_using!(data.vote_count_rankings, VoteCount),
+        other => unknown_attribution_kind(other),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handlers::movers::{Baseline, MoversBaselineCache};
+    use crate::models::{AggregationTimeRange, AttributionKind};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    #[test]
+    fn cached_baseline_is_used_until_generation_changes() {
+        let cache = MoversBaselineCache::default();
+        let kind = AttributionKind::Break;
+        let time_range = AggregationTimeRange::All;
+        let baseline_of = |rank| Arc::new(Baseline::from([(Uuid::from_u128(1), (rank, 0))]));
+
+        cache.insert(kind, time_range, 1, baseline_of(1));
+        assert_eq!(cache.get(kind, time_range, 1), Some(baseline_of(1)));
+        assert_eq!(cache.get(kind, time_range, 2), None);
+        assert_eq!(cache.get(kind, AggregationTimeRange::LastOneDay, 1), None);
+
+        cache.insert(kind, time_range, 2, baseline_of(2));
+        // 更新前に読み始めたものが遅れて入ってきても、新しいものは上書きされない
+        cache.insert(kind, time_range, 1, baseline_of(3));
+        assert_eq!(cache.get(kind, time_range, 2), Some(baseline_of(2)));
+    }
+}
@@ -0,0 +1,349 @@
+use crate::auth::API_KEY_HEADER;
+use crate::handlers::{
+    admin, badge, card, graphql, history, leaderboard, movers, presentation_models, ranking,
+    updates, websocket,
+};
+use crate::models::{AggregationTimeRange, AttributionKind, AttributionUnit};
+use actix_web::{HttpResponse, Responder};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+struct ApiKeySecurity;
+
+impl Modify for ApiKeySecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+    }
+}
+
+/// ハンドラとプレゼンテーション層の型から生成される OpenAPI ドキュメント
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "SeichiRankingBFF"),
+    servers((url = "/v1")),
+    paths(
+        ranking::ranking,
+        leaderboard::leaderboard_image,
+        ranking::player_rank,
+        history::player_rank_history,
+        card::player_rank_card,
+        badge::player_rank_badge,
+        movers::movers,
+        updates::ranking_stream,
+        websocket::websocket,
+        graphql::graphql,
+        admin::rehydrate,
+        admin::metrics,
+        admin::privacy_list,
+        admin::anonymize_player,
+        admin::unanonymize_player,
+    ),
+    components(schemas(
+        AttributionKind,
+        AggregationTimeRange,
+        AttributionUnit,
+        presentation_models::Player,
+        presentation_models::RankingRecord,
+        presentation_models::PlayerRankingRecord,
+        presentation_models::RankHistoryPoint,
+        presentation_models::RehydrationResult,
+        presentation_models::RehydrationReport,
+    )),
+    modifiers(&ApiKeySecurity)
+)]
+pub struct ApiDoc;
+
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/openapi.json")]
+pub async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handlers::openapi::ApiDoc;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+    use utoipa::OpenApi;
+
+    /// `docs/api.yaml` にはない、このサーバー独自のエンドポイント
+    const EXTENSIONS: &[&str] = &[
+        "get /ranking/image",
+        "get /player-ranks/{uuid}/history",
+        "get /player-ranks/{uuid}/card",
+        "get /player-ranks/{uuid}/badge",
+        "get /movers",
+        "get /ranking/stream",
+        "get /ws",
+        "post /graphql",
+        "post /admin/rehydrate",
+        "get /admin/metrics",
+        "get /admin/privacy",
+        "put /admin/privacy/{uuid}",
+        "delete /admin/privacy/{uuid}",
+    ];
+
+    /// `value` が `$ref` であれば、 `document` の中の参照先を返す。
+    fn resolve<'a>(document: &'a Value, value: &'a Value) -> &'a Value {
+        match value.get("$ref").and_then(Value::as_str) {
+            Some(reference) => {
+                let pointer = reference.trim_start_matches('#');
+                resolve(document, document.pointer(pointer).unwrap_or(&Value::Null))
+            }
+            None => value,
+        }
+    }
+
+    /// `null` を含めた型の集合。 OpenAPI 3.0 の `nullable: true` は 3.1 と同じく `null` 型として扱う。
+    fn types(schema: &Value) -> BTreeSet<String> {
+        let mut types = match schema.get("type") {
+            Some(Value::String(t)) => BTreeSet::from([t.clone()]),
+            Some(Value::Array(ts)) => ts
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect(),
+            _ => BTreeSet::new(),
+        };
+        if schema.get("nullable") == Some(&Value::Bool(true)) {
+            types.insert("null".to_string());
+        }
+        types
+    }
+
+    /// 生成されたスキーマが、写しのスキーマを満たすクライアントを壊さないかを調べる。
+    /// 写しにないプロパティが生成されたスキーマに増えていることは許す。
+    fn compare_schemas(
+        location: &str,
+        vendored_document: &Value,
+        vendored: &Value,
+        generated_document: &Value,
+        generated: &Value,
+        differences: &mut Vec<String>,
+    ) {
+        let vendored = resolve(vendored_document, vendored);
+        let generated = resolve(generated_document, generated);
+
+        // 写しで `null` になりうる値が、生成されたスキーマで `null` にならないことは許す
+        let (vendored_types, mut generated_types) = (types(vendored), types(generated));
+        if vendored_types.contains("null") {
+            generated_types.insert("null".to_string());
+        }
+        if vendored_types != generated_types {
+            differences.push(format!(
+                "{location}: type {vendored_types:?} is generated as {generated_types:?}"
+            ));
+            return;
+        }
+
+        for key in ["format", "enum"] {
+            if let Some(expected) = vendored.get(key) {
+                if generated.get(key) != Some(expected) {
+                    differences.push(format!(
+                        "{location}: {key} {expected} is generated as {}",
+                        generated.get(key).unwrap_or(&Value::Null)
+                    ));
+                }
+            }
+        }
+
+        if let Some(items) = vendored.get("items") {
+            compare_schemas(
+                &format!("{location}[]"),
+                vendored_document,
+                items,
+                generated_document,
+                generated.get("items").unwrap_or(&Value::Null),
+                differences,
+            );
+        }
+
+        for required in vendored["required"].as_array().into_iter().flatten() {
+            let is_required = generated["required"]
+                .as_array()
+                .is_some_and(|r| r.contains(required));
+            if !is_required {
+                differences.push(format!("{location}: {required} is not required"));
+            }
+        }
+
+        for (name, property) in vendored["properties"].as_object().into_iter().flatten() {
+            match generated["properties"].get(name) {
+                Some(generated_property) => compare_schemas(
+                    &format!("{location}.{name}"),
+                    vendored_document,
+                    property,
+                    generated_document,
+                    generated_property,
+                    differences,
+                ),
+                None => differences.push(format!("{location}: property {name} is missing")),
+            }
+        }
+    }
+
+    fn operations(document: &Value) -> Vec<(String, &Value)> {
+        document["paths"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .flat_map(|(path, item)| {
+                item.as_object()
+                    .into_iter()
+                    .flatten()
+                    .map(move |(method, operation)| (format!("{method} {path}"), operation))
+            })
+            .collect()
+    }
+
+    fn find_parameter<'a>(
+        document: &'a Value,
+        operation: &'a Value,
+        name: &str,
+        location: &str,
+    ) -> Option<&'a Value> {
+        operation["parameters"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|parameter| resolve(document, parameter))
+            .find(|parameter| parameter["name"] == name && parameter["in"] == location)
+    }
+
+    #[test]
+    fn generated_document_matches_vendored_api_yaml() {
+        let vendored: Value = serde_yaml::from_str(include_str!("../../docs/api.yaml")).unwrap();
+        let generated = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut differences = vec![];
+
+        for (operation_name, vendored_operation) in operations(&vendored) {
+            let (method, path) = operation_name.split_once(' ').unwrap();
+            let Some(generated_operation) = generated["paths"][path].get(method) else {
+                differences.push(format!("{operation_name} is not generated"));
+                continue;
+            };
+
+            for parameter in vendored_operation["parameters"]
+                .as_array()
+                .into_iter()
+                .flatten()
+            {
+                let parameter = resolve(&vendored, parameter);
+                let (name, location) = (
+                    parameter["name"].as_str().unwrap_or_default(),
+                    parameter["in"].as_str().unwrap_or_default(),
+                );
+                let Some(generated_parameter) =
+                    find_parameter(&generated, generated_operation, name, location)
+                else {
+                    differences.push(format!("{operation_name}: parameter {name} is missing"));
+                    continue;
+                };
+
+                let required = |p: &Value| p["required"].as_bool().unwrap_or(false);
+                if required(parameter) != required(generated_parameter) {
+                    differences.push(format!(
+                        "{operation_name}: parameter {name} has a different `required`"
+                    ));
+                }
+
+                compare_schemas(
+                    &format!("{operation_name}: parameter {name}"),
+                    &vendored,
+                    &parameter["schema"],
+                    &generated,
+                    &generated_parameter["schema"],
+                    &mut differences,
+                );
+            }
+
+            for (status, response) in vendored_operation["responses"]
+                .as_object()
+                .into_iter()
+                .flatten()
+            {
+                let Some(generated_response) = generated_operation["responses"].get(status) else {
+                    differences.push(format!("{operation_name}: response {status} is missing"));
+                    continue;
+                };
+
+                if let Some(schema) = response.pointer("/content/application~1json/schema") {
+                    compare_schemas(
+                        &format!("{operation_name}: response {status}"),
+                        &vendored,
+                        schema,
+                        &generated,
+                        generated_response
+                            .pointer("/content/application~1json/schema")
+                            .unwrap_or(&Value::Null),
+                        &mut differences,
+                    );
+                }
+            }
+        }
+
+        let vendored_operations = operations(&vendored)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<BTreeSet<_>>();
+        for (operation_name, _) in operations(&generated) {
+            if !vendored_operations.contains(&operation_name)
+                && !EXTENSIONS.contains(&operation_name.as_str())
+            {
+                differences.push(format!(
+                    "{operation_name} is neither in docs/api.yaml nor listed as an extension"
+                ));
+            }
+        }
+
+        assert!(
+            differences.is_empty(),
+            "generated OpenAPI document differs from docs/api.yaml:\n{}",
+            differences.join("\n")
+        );
+    }
+
+    /// ドキュメントに含めない、 `src/handlers` の中のエンドポイント
+    const UNDOCUMENTED: &[&str] = &["get /openapi.json"];
+
+    #[test]
+    fn every_route_is_documented() {
+        let generated = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let documented = operations(&generated)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<BTreeSet<_>>();
+
+        let handlers_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/handlers");
+        let mut routes = BTreeSet::new();
+        for entry in std::fs::read_dir(handlers_dir).unwrap() {
+            let source = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            for line in source.lines() {
+                let Some(route) = line.trim().strip_prefix("#[actix_web::") else {
+                    continue;
+                };
+                let Some((method, rest)) = route.split_once("(\"") else {
+                    continue;
+                };
+                let path = rest.split_once('"').unwrap().0;
+                routes.insert(format!("{method} {path}"));
+            }
+        }
+
+        let undocumented = routes
+            .iter()
+            .filter(|route| {
+                !documented.contains(route.as_str()) && !UNDOCUMENTED.contains(&route.as_str())
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            undocumented.is_empty(),
+            "routes missing from ApiDoc: {undocumented:?}"
+        );
+        assert!(routes.contains("get /ranking"));
+    }
+}
@@ -1,43 +1,124 @@
-use crate::models::{AggregatedPlayerAttribution, RankedAttributionRecord};
-use chrono::{DateTime, Utc};
+use crate::app_models::PrivacyList;
+use crate::handlers::formatting::{format_value, Locale};
+use crate::models::{self, AggregatedPlayerAttribution, AttributionUnit, RankedAttributionRecord};
+use crate::snapshot_store::PlayerSnapshotRecord;
+use async_graphql::SimpleObject;
+use chrono::{DateTime, TimeZone, Utc};
 use serde::Serialize;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub(crate) struct Player {
+    // 名前を公開しないプレーヤーについては、元の UUID の代わりに起動するたびに変わる仮の UUID になる。
     pub(crate) uuid: Uuid,
     pub(crate) name: String,
     // OpenAPI Specificationでの `format: date-time` によりRFC3339形式が要求されているが、
     // chrono 0.4.19 の `impl<Tz: TimeZone> serde::Serialize for DateTime<Tz>` は
     // RFC3339でシリアライズするようになっている。
+    // 名前を公開しないプレーヤーについては、 [`anonymized_last_quit`] の固定の日時になる。
     pub(crate) last_quit: DateTime<Utc>,
 }
 
-#[derive(Serialize)]
+/// 名前を公開しないプレーヤーの名前の代わりに表示するラベル
+pub(crate) const ANONYMIZED_PLAYER_NAME: &str = "匿名のプレーヤー";
+
+/// 名前を公開しないプレーヤーの最終ログアウト日時の代わりに表示する日時 (`1970-01-01T00:00:00Z`)。
+/// `last_quit` は `null` にならないことが公開されているので、 `null` の代わりにこの日時を使う。
+pub(crate) fn anonymized_last_quit() -> DateTime<Utc> {
+    Utc.timestamp_opt(0, 0).unwrap()
+}
+
+/// レスポンスに表示する `player` の名前。 `privacy_list` にいるプレーヤーについては匿名のラベルを返す。
+pub(crate) fn displayed_player_name<'a>(
+    player: &'a models::Player,
+    privacy_list: &PrivacyList,
+) -> &'a str {
+    if privacy_list.contains(player.uuid) {
+        ANONYMIZED_PLAYER_NAME
+    } else {
+        &player.name
+    }
+}
+
+/// レスポンスに表示する `player` の UUID。 `privacy_list` にいるプレーヤーについては仮の UUID を返す。
+pub(crate) fn displayed_player_uuid(player: &models::Player, privacy_list: &PrivacyList) -> Uuid {
+    if privacy_list.contains(player.uuid) {
+        privacy_list.pseudonym(player.uuid)
+    } else {
+        player.uuid
+    }
+}
+
+pub(crate) fn player_to_presentation_player(
+    player: &models::Player,
+    privacy_list: &PrivacyList,
+) -> Player {
+    let last_quit = if privacy_list.contains(player.uuid) {
+        anonymized_last_quit()
+    } else {
+        player.last_quit
+    };
+
+    Player {
+        uuid: displayed_player_uuid(player, privacy_list),
+        name: displayed_player_name(player, privacy_list).to_string(),
+        last_quit,
+    }
+}
+
+#[derive(Serialize, ToSchema, SimpleObject)]
 pub(crate) struct RankingRecord {
     pub(crate) rank_position: u32,
     pub(crate) value: u64,
+    // 前回の更新から順位が上がった場合は正、下がった場合は負になる。
+    // 前回の更新時にランキングに含まれていなかった場合は `null` になる。
+    pub(crate) rank_delta: Option<i64>,
+    pub(crate) value_delta: Option<i64>,
+    #[graphql(skip)]
+    pub(crate) unit: AttributionUnit,
+    // `Accept-Language` で対応している言語が指定された場合にのみ含まれる
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[graphql(skip)]
+    pub(crate) formatted_value: Option<String>,
 }
 
-#[derive(Serialize)]
+impl RankingRecord {
+    /// `locale` が `Some` の場合、その言語で人が読みやすい形にした値を加える。
+    pub(crate) fn localized(self, locale: Option<Locale>) -> Self {
+        Self {
+            formatted_value: locale.map(|locale| format_value(self.unit, self.value, locale)),
+            ..self
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 pub(crate) struct PlayerRankingRecord {
     pub(crate) player: Player,
     pub(crate) record: RankingRecord,
 }
 
+impl PlayerRankingRecord {
+    pub(crate) fn localized(self, locale: Option<Locale>) -> Self {
+        Self {
+            record: self.record.localized(locale),
+            ..self
+        }
+    }
+}
+
 pub(crate) fn ranked_record_to_presentation_player_ranking_record<
     Attribution: AggregatedPlayerAttribution,
 >(
     ranked_record: &RankedAttributionRecord<Attribution>,
+    privacy_list: &PrivacyList,
 ) -> PlayerRankingRecord {
-    let player = ranked_record.attribution_record.player.clone();
-
     PlayerRankingRecord {
-        player: Player {
-            uuid: player.uuid,
-            name: player.name,
-            last_quit: player.last_quit,
-        },
+        player: player_to_presentation_player(
+            &ranked_record.attribution_record.player,
+            privacy_list,
+        ),
         record: ranked_record_to_presentation_ranking_record(ranked_record),
     }
 }
@@ -47,8 +128,56 @@ pub(crate) fn ranked_record_to_presentation_ranking_record<
 >(
     ranked_record: &RankedAttributionRecord<Attribution>,
 ) -> RankingRecord {
+    let value = ranked_record.attribution_record.attribution.raw_u64_data();
+    let previous = ranked_record.previous.as_ref();
+
     RankingRecord {
         rank_position: ranked_record.rank,
-        value: ranked_record.attribution_record.attribution.raw_u64_data(),
+        value,
+        rank_delta: previous.map(|p| i64::from(p.rank) - i64::from(ranked_record.rank)),
+        value_delta: previous.map(|p| {
+            let previous_value = p.attribution.raw_u64_data();
+            if value >= previous_value {
+                i64::try_from(value - previous_value).unwrap_or(i64::MAX)
+            } else {
+                i64::try_from(previous_value - value).map_or(i64::MIN, |d| -d)
+            }
+        }),
+        unit: Attribution::UNIT,
+        formatted_value: None,
     }
 }
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RankHistoryPoint {
+    pub(crate) at: DateTime<Utc>,
+    pub(crate) rank_position: u32,
+    pub(crate) value: u64,
+}
+
+pub(crate) fn player_snapshot_record_to_presentation_rank_history_point<
+    Attribution: AggregatedPlayerAttribution,
+>(
+    record: &PlayerSnapshotRecord<Attribution>,
+) -> RankHistoryPoint {
+    RankHistoryPoint {
+        at: record.taken_at,
+        rank_position: record.rank,
+        value: record.attribution.raw_u64_data(),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RehydrationResult {
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+    pub(crate) succeeded: bool,
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RehydrationReport {
+    pub(crate) succeeded: bool,
+    pub(crate) time_range: Option<String>,
+    pub(crate) results: Vec<RehydrationResult>,
+}
@@ -1,42 +1,111 @@
 use crate::app_models::AppState;
+use crate::config::RankingsConfig;
+use crate::handlers::discord::{
+    player_rank_to_discord_message, ranking_to_discord_message, MAX_EMBED_FIELDS,
+};
+use crate::handlers::formatting::locale_from_request;
 use crate::handlers::presentation_models::{
     ranked_record_to_presentation_player_ranking_record,
     ranked_record_to_presentation_ranking_record,
 };
-use crate::models::AggregationTimeRange;
+#[allow(unused_imports)] // `utoipa::path` の中でのみ使われる
+use crate::handlers::presentation_models::{PlayerRankingRecord, RankingRecord};
+use crate::models::{
+    AggregationTimeRange, AttributionKind, BreakCount, BuildCount, PlayTicks, VoteCount,
+};
+use crate::snapshot_store::{parse_snapshot_time, RankingSnapshotStore};
 use actix_web::body::BoxBody;
+use actix_web::http::header;
 use actix_web::web::Path;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use log::error;
 use qstring::QString;
 use std::str::FromStr;
+use strum::EnumString;
 use uuid::Uuid;
 
-fn duration_not_recognized_response(duration_str: &str) -> HttpResponse<BoxBody> {
+/// レスポンスの本文の形式
+#[derive(Clone, Copy, PartialEq, Eq, EnumString)]
+enum ResponseFormat {
+    /// API 仕様どおりの JSON
+    #[strum(serialize = "json")]
+    Json,
+    /// そのまま Discord に投稿できる、埋め込み (embed) を含むメッセージの JSON
+    #[strum(serialize = "discord")]
+    Discord,
+}
+
+fn format_not_recognized_response(format_str: &str) -> HttpResponse<BoxBody> {
+    HttpResponse::BadRequest().body(format!("{format_str} is not a recognized response format."))
+}
+
+pub(crate) fn duration_not_recognized_response(duration_str: &str) -> HttpResponse<BoxBody> {
     HttpResponse::BadRequest().body(format!(
         "{duration_str} is not a recognized duration specifier."
     ))
 }
 
-fn unknown_attribution_kind(attribution_kind: &str) -> HttpResponse<BoxBody> {
+pub(crate) fn unknown_attribution_kind(attribution_kind: &str) -> HttpResponse<BoxBody> {
     HttpResponse::BadRequest().body(format!(
         "{attribution_kind} is not a recognized attribution specifier"
     ))
 }
 
-fn time_range_from_qs(qs: &QString) -> &str {
+pub(crate) fn time_not_recognized_response(time_str: &str) -> HttpResponse<BoxBody> {
+    HttpResponse::BadRequest().body(format!("{time_str} is not a recognized point in time."))
+}
+
+pub(crate) fn snapshots_disabled_response() -> HttpResponse<BoxBody> {
+    HttpResponse::NotFound().body("historical rankings are not available on this server")
+}
+
+pub(crate) fn snapshot_not_found(
+    attribution_kind: &str,
+    time_range: AggregationTimeRange,
+    at: DateTime<Utc>,
+) -> HttpResponse<BoxBody> {
+    HttpResponse::NotFound().body(format!(
+        "no snapshot for kind={attribution_kind}, time-range={time_range} taken at or before {}",
+        at.to_rfc3339()
+    ))
+}
+
+pub(crate) fn time_range_from_qs(qs: &QString) -> &str {
     qs.get("time_range").unwrap_or("all")
 }
 
-fn parse_usize_param(qs: &QString, param_name: &str) -> Option<usize> {
+pub(crate) fn parse_usize_param(qs: &QString, param_name: &str) -> Option<usize> {
     qs.get(param_name)
         .and_then(|offset_str| offset_str.parse::<usize>().ok())
 }
 
-const RANKING_MAX_LIMIT_PER_REQUEST: usize = 1000;
-
+#[utoipa::path(
+    get,
+    path = "/ranking",
+    params(
+        ("type" = Option<AttributionKind>, Query, description = "ランキングの種類。既定値は `break`"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "集計期間。既定値は `all`"),
+        ("limit" = Option<usize>, Query, description = "取得するレコードの数。既定値は 20"),
+        ("offset" = Option<usize>, Query, description = "先頭から読み飛ばすレコードの数。既定値は 0"),
+        ("at" = Option<String>, Query, description = "指定すると、その時点で最新だったスナップショットから取得する"),
+        ("format" = Option<String>, Query, description = "`discord` を指定すると、 Discord の埋め込み (embed) を含むメッセージとして返す。このとき `limit` は 25 まで"),
+        ("Accept-Language" = Option<String>, Header, description = "`ja` か `en` を含む場合、その言語で人が読みやすい形にした値を `formatted_value` に含める"),
+    ),
+    responses(
+        (status = 200, body = [PlayerRankingRecord]),
+        (status = 400, description = "クエリパラメータが不正"),
+        (status = 404, description = "`at` に対応するスナップショットがない"),
+    )
+)]
 #[allow(clippy::future_not_send)]
 #[actix_web::get("/ranking")]
-pub async fn ranking(req: HttpRequest, data: web::Data<&'static AppState>) -> impl Responder {
+pub async fn ranking(
+    req: HttpRequest,
+    data: web::Data<&'static AppState>,
+    rankings_config: web::Data<RankingsConfig>,
+    snapshot_store: Option<web::Data<RankingSnapshotStore>>,
+) -> impl Responder {
     let qs: QString = req.query_string().into();
 
     let time_range_specifier = time_range_from_qs(&qs);
@@ -45,38 +114,106 @@ pub async fn ranking(req: HttpRequest, data: web::Data<&'static AppState>) -> im
         Err(_) => return duration_not_recognized_response(time_range_specifier),
     };
 
+    let attribution_kind = qs.get("type").unwrap_or("break");
+
     let limit = parse_usize_param(&qs, "limit").unwrap_or(20);
     let offset = parse_usize_param(&qs, "offset").unwrap_or(0);
 
-    if limit > RANKING_MAX_LIMIT_PER_REQUEST {
+    let format_specifier = qs.get("format").unwrap_or("json");
+    let format = match ResponseFormat::from_str(format_specifier) {
+        Ok(f) => f,
+        Err(_) => return format_not_recognized_response(format_specifier),
+    };
+    let locale = locale_from_request(&req);
+
+    // 未知の種類はこの後で弾かれる
+    let max_limit = AttributionKind::from_str(attribution_kind).map_or(usize::MAX, |kind| {
+        rankings_config.for_kind(kind).max_limit()
+    });
+
+    if limit > max_limit {
         return HttpResponse::BadRequest().body(format!("{limit} is too large for a limit"));
     }
 
-    let attribution_kind = qs.get("type").unwrap_or("break");
+    if format == ResponseFormat::Discord && limit > MAX_EMBED_FIELDS {
+        return HttpResponse::BadRequest().body(format!(
+            "{limit} is too large for a limit; at most {MAX_EMBED_FIELDS} records fit in a Discord embed"
+        ));
+    }
+
+    let at = match qs.get("at") {
+        None => None,
+        Some(at_specifier) => match parse_snapshot_time(at_specifier) {
+            Some(at) => Some(at),
+            None => return time_not_recognized_response(at_specifier),
+        },
+    };
 
     macro_rules! respond_using {
-        ($ranking:expr) => {{
-            let paginated_ranking = $ranking
-                .for_time_range(time_range)
-                .read()
-                .await
-                .paginate(offset, limit);
-
-            HttpResponse::Ok().json(
-                paginated_ranking
-                    .0
-                    .into_iter()
-                    .map(|r| ranked_record_to_presentation_player_ranking_record(&r))
-                    .collect::<Vec<_>>(),
-            )
+        ($ranking:expr, $attribution:ty) => {{
+            let paginated_ranking = match at {
+                None => $ranking
+                    .for_time_range(time_range)
+                    .read()
+                    .await
+                    .paginate(offset, limit),
+                Some(at) => {
+                    let Some(store) = snapshot_store.clone() else {
+                        return snapshots_disabled_response();
+                    };
+
+                    let historical_ranking = web::block(move || {
+                        store.paginate_at::<$attribution>(time_range, at, offset, limit)
+                    })
+                    .await;
+
+                    match historical_ranking {
+                        Ok(Ok(Some(slice))) => slice,
+                        Ok(Ok(None)) => {
+                            return snapshot_not_found(attribution_kind, time_range, at);
+                        }
+                        Ok(Err(e)) => {
+                            error!("Error reading ranking snapshot: {e}");
+                            return HttpResponse::InternalServerError().finish();
+                        }
+                        Err(e) => {
+                            error!("Error reading ranking snapshot: {e}");
+                            return HttpResponse::InternalServerError().finish();
+                        }
+                    }
+                }
+            };
+
+            match format {
+                ResponseFormat::Json => HttpResponse::Ok()
+                    .insert_header((header::VARY, "Accept-Language"))
+                    .json(
+                        paginated_ranking
+                            .0
+                            .into_iter()
+                            .map(|r| {
+                                ranked_record_to_presentation_player_ranking_record(
+                                    &r,
+                                    &data.privacy_list,
+                                )
+                                .localized(locale)
+                            })
+                            .collect::<Vec<_>>(),
+                    ),
+                ResponseFormat::Discord => HttpResponse::Ok().json(ranking_to_discord_message(
+                    &paginated_ranking.0,
+                    time_range,
+                    &data.privacy_list,
+                )),
+            }
         }};
     }
 
     match attribution_kind {
-        "break" => respond_using!(data.break_count_rankings),
-        "build" => respond_using!(data.build_count_rankings),
-        "play_ticks" => respond_using!(data.play_ticks_rankings),
-        "vote_count" => respond_using!(data.vote_count_rankings),
+        "break" => respond_using!(data.break_count_rankings, BreakCount),
+        "build" => respond_using!(data.build_count_rankings, BuildCount),
+        "play_ticks" => respond_using!(data.play_ticks_rankings, PlayTicks),
+        "vote_count" => respond_using!(data.vote_count_rankings, VoteCount),
         other => unknown_attribution_kind(other),
     }
 }
@@ -91,6 +228,22 @@ fn record_with_uuid_not_found(
     ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/player-ranks/{uuid}",
+    params(
+        ("uuid" = Uuid, Path, description = "プレーヤーの UUID"),
+        ("type" = Option<AttributionKind>, Query, description = "ランキングの種類。既定値は `break`"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "集計期間。既定値は `all`"),
+        ("format" = Option<String>, Query, description = "`discord` を指定すると、 Discord の埋め込み (embed) を含むメッセージとして返す"),
+        ("Accept-Language" = Option<String>, Header, description = "`ja` か `en` を含む場合、その言語で人が読みやすい形にした値を `formatted_value` に含める"),
+    ),
+    responses(
+        (status = 200, body = RankingRecord),
+        (status = 400, description = "クエリパラメータが不正"),
+        (status = 404, description = "プレーヤーがランキングに含まれていない"),
+    )
+)]
 #[allow(clippy::future_not_send)]
 #[actix_web::get("/player-ranks/{uuid}")]
 pub async fn player_rank(
@@ -108,6 +261,13 @@ pub async fn player_rank(
 
     let attribution_kind = qs.get("type").unwrap_or("break");
 
+    let format_specifier = qs.get("format").unwrap_or("json");
+    let format = match ResponseFormat::from_str(format_specifier) {
+        Ok(f) => f,
+        Err(_) => return format_not_recognized_response(format_specifier),
+    };
+    let locale = locale_from_request(&req);
+
     let player_uuid = path.into_inner();
 
     macro_rules! respond_using {
@@ -125,7 +285,16 @@ pub async fn player_rank(
                 }
             };
 
-            HttpResponse::Ok().json(ranked_record_to_presentation_ranking_record(&record))
+            match format {
+                ResponseFormat::Json => HttpResponse::Ok()
+                    .insert_header((header::VARY, "Accept-Language"))
+                    .json(ranked_record_to_presentation_ranking_record(&record).localized(locale)),
+                ResponseFormat::Discord => HttpResponse::Ok().json(player_rank_to_discord_message(
+                    &record,
+                    time_range,
+                    &data.privacy_list,
+                )),
+            }
         }};
     }
 
@@ -137,3 +306,57 @@ pub async fn player_rank(
         other => unknown_attribution_kind(other),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::AppState;
+    use crate::config::RankingsConfig;
+    use crate::handlers::ranking::ranking;
+    use crate::models::AggregationTimeRange;
+    use crate::test_fixtures::records_with_values;
+    use actix_web::web::Data;
+    use actix_web::{test, App};
+    use uuid::Uuid;
+
+    #[actix_web::test]
+    async fn uuid_of_private_player_appears_nowhere_in_response() {
+        let state: &'static AppState = Box::leak(Box::default());
+        state
+            .break_count_rankings
+            .for_time_range(AggregationTimeRange::All)
+            .write()
+            .await
+            .hydrate_record_set(records_with_values(&[30, 20, 10]));
+        let private = Uuid::from_u128(2);
+        state.privacy_list.insert(private);
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(RankingsConfig::default()))
+                .service(ranking),
+        )
+        .await;
+
+        for uri in ["/ranking", "/ranking?format=discord"] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let body = test::call_and_read_body(&app, request).await;
+            let body = std::str::from_utf8(&body).unwrap();
+
+            for form in [
+                private.hyphenated().to_string(),
+                private.simple().to_string(),
+            ] {
+                assert!(!body.contains(&form), "{uri} leaks {form}: {body}");
+            }
+        }
+
+        // 公開しているプレーヤーの UUID はそのままで、仮の UUID は同じプロセスの中では変わらない
+        let request = test::TestRequest::get().uri("/ranking").to_request();
+        let records: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(records[0]["player"]["uuid"], Uuid::from_u128(1).to_string());
+        assert_eq!(
+            records[1]["player"]["uuid"],
+            state.privacy_list.pseudonym(private).to_string()
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use crate::app_models::{shutdown_requested, AppState, RankingUpdate};
+use crate::config::RankingsConfig;
+use crate::handlers::presentation_models::{
+    ranked_record_to_presentation_player_ranking_record,
+    ranked_record_to_presentation_ranking_record,
+};
+use crate::handlers::ranking::{
+    duration_not_recognized_response, parse_usize_param, time_range_from_qs,
+    unknown_attribution_kind,
+};
+use crate::models::{AggregationTimeRange, AttributionKind};
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use futures_util::stream;
+use log::debug;
+use qstring::QString;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{Instant, Interval};
+use uuid::Uuid;
+
+/// 何も送るものがなくても、この間隔でコメントを送って接続を保つ
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy)]
+enum Target {
+    /// 上位 `n` 件
+    Top(usize),
+    Player(Uuid),
+}
+
+#[derive(Clone, Copy)]
+struct Subscription {
+    kind: AttributionKind,
+    time_range: AggregationTimeRange,
+    target: Target,
+}
+
+impl Subscription {
+    fn is_interested_in(&self, update: RankingUpdate) -> bool {
+        update.kind == self.kind && update.time_range == self.time_range
+    }
+}
+
+fn sse_event(event: &str, data: &impl Serialize) -> Bytes {
+    Bytes::from(format!(
+        "event: {event}\ndata: {}\n\n",
+        serde_json::json!(data)
+    ))
+}
+
+/// 購読しているランキングの現在の状態を、一つのイベントとして書き出す。
+async fn render_event(state: &AppState, subscription: Subscription) -> Bytes {
+    macro_rules! render_using {
+        ($ranking:expr) => {{
+            let ranking = $ranking
+                .for_time_range(subscription.time_range)
+                .read()
+                .await;
+
+            match subscription.target {
+                Target::Top(limit) => sse_event(
+                    "ranking",
+                    &ranking
+                        .paginate(0, limit)
+                        .0
+                        .iter()
+                        .map(|record| {
+                            ranked_record_to_presentation_player_ranking_record(
+                                record,
+                                &state.privacy_list,
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+                Target::Player(uuid) => sse_event(
+                    "player-rank",
+                    &ranking
+                        .record_with_uuid(uuid)
+                        .map(|record| ranked_record_to_presentation_ranking_record(&record)),
+                ),
+            }
+        }};
+    }
+
+    match subscription.kind {
+        AttributionKind::Break => render_using!(state.break_count_rankings),
+        AttributionKind::Build => render_using!(state.build_count_rankings),
+        AttributionKind::PlayTicks => render_using!(state.play_ticks_rankings),
+        AttributionKind::VoteCount => render_using!(state.vote_count_rankings),
+    }
+}
+
+struct EventStream {
+    state: &'static AppState,
+    subscription: Subscription,
+    updates: broadcast::Receiver<RankingUpdate>,
+    shutdown: watch::Receiver<bool>,
+    heartbeat: Interval,
+    sent_initial_event: bool,
+}
+
+impl EventStream {
+    /// 次に送るイベント。接続を閉じるべきときは `None` を返す。
+    ///
+    /// クライアントが読むのが遅い間は通知が溜まるが、溜まりすぎた通知は捨てられる。
+    /// イベントは常にその時点の状態全体を表すので、最新の状態を一度送れば追いつける。
+    async fn next_event(&mut self) -> Option<Bytes> {
+        if !self.sent_initial_event {
+            self.sent_initial_event = true;
+            return Some(render_event(self.state, self.subscription).await);
+        }
+
+        loop {
+            tokio::select! {
+                update = self.updates.recv() => match update {
+                    Ok(update) if self.subscription.is_interested_in(update) => break,
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Event stream lagged behind by {skipped} ranking updates");
+                        break;
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                () = shutdown_requested(&mut self.shutdown) => return None,
+                _ = self.heartbeat.tick() => return Some(Bytes::from_static(b": heartbeat\n\n")),
+            }
+        }
+
+        Some(render_event(self.state, self.subscription).await)
+    }
+}
+
+/// ランキングが更新されるたびに、購読したランキングかプレーヤーの最新の状態を Server-Sent Events で送る。
+#[utoipa::path(
+    get,
+    path = "/ranking/stream",
+    params(
+        ("type" = Option<AttributionKind>, Query, description = "ランキングの種類。既定値は `break`"),
+        ("time_range" = Option<AggregationTimeRange>, Query, description = "集計期間。既定値は `all`"),
+        ("uuid" = Option<Uuid>, Query, description = "購読するプレーヤーの UUID。指定しない場合は上位のレコードを購読する"),
+        ("limit" = Option<usize>, Query, description = "購読する上位のレコードの数。既定値は 10"),
+    ),
+    responses(
+        (status = 200, body = String, content_type = "text/event-stream"),
+        (status = 400, description = "クエリパラメータが不正"),
+    )
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/ranking/stream")]
+pub async fn ranking_stream(
+    req: HttpRequest,
+    data: web::Data<&'static AppState>,
+    rankings_config: web::Data<RankingsConfig>,
+    shutdown: web::Data<watch::Receiver<bool>>,
+) -> impl Responder {
+    let qs: QString = req.query_string().into();
+
+    let time_range_specifier = time_range_from_qs(&qs);
+    let time_range = match AggregationTimeRange::from_str(time_range_specifier) {
+        Ok(r) => r,
+        Err(_) => return duration_not_recognized_response(time_range_specifier),
+    };
+
+    let attribution_kind = qs.get("type").unwrap_or("break");
+    let kind = match AttributionKind::from_str(attribution_kind) {
+        Ok(kind) => kind,
+        Err(_) => return unknown_attribution_kind(attribution_kind),
+    };
+
+    let target = match qs.get("uuid") {
+        Some(uuid_specifier) => match Uuid::from_str(uuid_specifier) {
+            Ok(uuid) => Target::Player(uuid),
+            Err(_) => {
+                return HttpResponse::BadRequest()
+                    .body(format!("{uuid_specifier} is not a valid UUID"))
+            }
+        },
+        None => {
+            let limit = parse_usize_param(&qs, "limit").unwrap_or(10);
+            if limit > rankings_config.for_kind(kind).max_limit() {
+                return HttpResponse::BadRequest()
+                    .body(format!("{limit} is too large for a limit"));
+            }
+            Target::Top(limit)
+        }
+    };
+
+    let events = EventStream {
+        state: data.get_ref(),
+        subscription: Subscription {
+            kind,
+            time_range,
+            target,
+        },
+        // 最初のイベントを書き出すより前に購読し、その間の更新を取りこぼさないようにする
+        updates: data.subscribe_ranking_updates(),
+        shutdown: shutdown.get_ref().clone(),
+        heartbeat: tokio::time::interval_at(
+            Instant::now() + HEARTBEAT_INTERVAL,
+            HEARTBEAT_INTERVAL,
+        ),
+        sent_initial_event: false,
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        // 圧縮されたりプロキシでバッファーされたりすると、イベントがすぐに届かなくなる
+        .insert_header(header::ContentEncoding::Identity)
+        .insert_header(("x-accel-buffering", "no"))
+        .streaming(stream::unfold(events, |mut events| async move {
+            let event = events.next_event().await?;
+            Some((Ok::<_, Infallible>(event), events))
+        }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::{rehydrate_kind, AppState};
+    use crate::config::RankingsConfig;
+    use crate::handlers::updates::{EventStream, Subscription, Target, HEARTBEAT_INTERVAL};
+    use crate::models::{AggregationTimeRange, AttributionKind};
+    use crate::test_fixtures::single_player_providers;
+    use tokio::sync::watch;
+    use tokio::time::Instant;
+    use uuid::Uuid;
+
+    #[actix_web::test]
+    async fn push_player_rank_after_each_rehydration() {
+        let state: &'static AppState = Box::leak(Box::default());
+        let providers = single_player_providers();
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let mut events = EventStream {
+            state,
+            subscription: Subscription {
+                kind: AttributionKind::Build,
+                time_range: AggregationTimeRange::LastOneDay,
+                target: Target::Player(Uuid::from_u128(1)),
+            },
+            updates: state.subscribe_ranking_updates(),
+            shutdown,
+            heartbeat: tokio::time::interval_at(
+                Instant::now() + HEARTBEAT_INTERVAL,
+                HEARTBEAT_INTERVAL,
+            ),
+            sent_initial_event: false,
+        };
+
+        assert_eq!(
+            events.next_event().await.unwrap(),
+            "event: player-rank\ndata: null\n\n"
+        );
+
+        // 購読していないランキングの更新は無視される
+        for kind in [AttributionKind::Break, AttributionKind::Build] {
+            rehydrate_kind(
+                kind,
+                None,
+                state,
+                &providers,
+                &RankingsConfig::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            events.next_event().await.unwrap(),
+            "event: player-rank\ndata: {\"rank_delta\":null,\"rank_position\":1,\"unit\":\"count\",\"value\":42,\"value_delta\":null}\n\n"
+        );
+
+        shutdown_sender.send(true).unwrap();
+        assert_eq!(events.next_event().await, None);
+    }
+}
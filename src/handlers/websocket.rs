@@ -0,0 +1,563 @@
+use crate::app_models::{shutdown_requested, AppState, PrivacyList, RankingUpdate};
+use crate::config::RankingsConfig;
+use crate::handlers::presentation_models::{
+    displayed_player_uuid, ranked_record_to_presentation_player_ranking_record, PlayerRankingRecord,
+};
+use crate::models::{
+    AggregatedPlayerAttribution, AggregationTimeRange, AttributionKind, RankedAttributionRecord,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, Message, MessageStream, Session};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// この間隔で ping を送る。その二倍の間 pong が返ってこなければ接続を閉じる。
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// 一つの接続で同時に購読できる数の上限
+const MAX_SUBSCRIPTIONS: usize = 100;
+
+/// 購読の対象
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Target {
+    /// 上位 `n` 件
+    Top(usize),
+    Player(Uuid),
+    /// 値がこの閾値に達したプレーヤー
+    Milestone(u64),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        id: String,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+        time_range: Option<String>,
+        #[serde(flatten)]
+        target: Target,
+    },
+    Unsubscribe {
+        id: String,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ServerMessage {
+    Subscribed {
+        id: String,
+    },
+    Unsubscribed {
+        id: String,
+    },
+    /// 前回送った時から変わったレコードと、対象から外れたプレーヤー (前回送ったレコードでの UUID)
+    Diff {
+        id: String,
+        updated: Vec<PlayerRankingRecord>,
+        removed: Vec<Uuid>,
+    },
+    /// 前回の更新から今回の更新までの間に、値が閾値に達したプレーヤー
+    Milestone {
+        id: String,
+        threshold: u64,
+        reached: Vec<PlayerRankingRecord>,
+    },
+    Error {
+        id: Option<String>,
+        message: String,
+    },
+}
+
+struct Subscription {
+    kind: AttributionKind,
+    time_range: AggregationTimeRange,
+    target: Target,
+    /// 最後に送ったレコードの、レスポンスに表示した UUID ごとの順位と値と、送った時の匿名の一覧の世代番号
+    sent: HashMap<Uuid, (u32, u64, u64)>,
+}
+
+impl Subscription {
+    fn is_interested_in(&self, update: RankingUpdate) -> bool {
+        update.kind == self.kind && update.time_range == self.time_range
+    }
+
+    fn diff<Attribution: AggregatedPlayerAttribution>(
+        &mut self,
+        id: &str,
+        records: &[RankedAttributionRecord<Attribution>],
+        privacy_list: &PrivacyList,
+    ) -> Option<ServerMessage> {
+        // 匿名の一覧が変わると名前や UUID の表示が変わりうるので、世代番号が変わったらすべて送り直す
+        let privacy_list_generation = privacy_list.generation();
+        let mut sent = HashMap::with_capacity(records.len());
+        let updated = records
+            .iter()
+            .filter(|record| {
+                let uuid = displayed_player_uuid(&record.attribution_record.player, privacy_list);
+                let current = (
+                    record.rank,
+                    record.attribution_record.attribution.raw_u64_data(),
+                    privacy_list_generation,
+                );
+                sent.insert(uuid, current);
+                self.sent.get(&uuid) != Some(&current)
+            })
+            .map(|record| ranked_record_to_presentation_player_ranking_record(record, privacy_list))
+            .collect::<Vec<_>>();
+        let removed = self
+            .sent
+            .keys()
+            .filter(|uuid| !sent.contains_key(uuid))
+            .copied()
+            .collect::<Vec<_>>();
+        self.sent = sent;
+
+        (!updated.is_empty() || !removed.is_empty()).then(|| ServerMessage::Diff {
+            id: id.to_string(),
+            updated,
+            removed,
+        })
+    }
+
+    /// 購読しているランキングを読み、前回から変わったことがあればそれを返す。
+    ///
+    /// `just_subscribed` の場合、閾値の購読については何も返さない (閾値に達したかどうかは更新の前後でしか判断できないため)。
+    async fn refresh(
+        &mut self,
+        id: &str,
+        state: &AppState,
+        just_subscribed: bool,
+    ) -> Option<ServerMessage> {
+        macro_rules! refresh_using {
+            ($ranking:expr) => {{
+                let ranking = $ranking.for_time_range(self.time_range).read().await;
+
+                match self.target {
+                    Target::Top(limit) => {
+                        self.diff(id, &ranking.paginate(0, limit).0, &state.privacy_list)
+                    }
+                    Target::Player(uuid) => self.diff(
+                        id,
+                        &Vec::from_iter(ranking.record_with_uuid(uuid)),
+                        &state.privacy_list,
+                    ),
+                    Target::Milestone(_) if just_subscribed => None,
+                    Target::Milestone(threshold) => {
+                        let reached = ranking
+                            .ranked_records()
+                            .iter()
+                            .filter(|record| {
+                                record.previous.as_ref().is_some_and(|previous| {
+                                    previous.attribution.raw_u64_data() < threshold
+                                        && threshold
+                                            <= record.attribution_record.attribution.raw_u64_data()
+                                })
+                            })
+                            .map(|record| {
+                                ranked_record_to_presentation_player_ranking_record(
+                                    record,
+                                    &state.privacy_list,
+                                )
+                            })
+                            .collect::<Vec<_>>();
+
+                        (!reached.is_empty()).then(|| ServerMessage::Milestone {
+                            id: id.to_string(),
+                            threshold,
+                            reached,
+                        })
+                    }
+                }
+            }};
+        }
+
+        match self.kind {
+            AttributionKind::Break => refresh_using!(state.break_count_rankings),
+            AttributionKind::Build => refresh_using!(state.build_count_rankings),
+            AttributionKind::PlayTicks => refresh_using!(state.play_ticks_rankings),
+            AttributionKind::VoteCount => refresh_using!(state.vote_count_rankings),
+        }
+    }
+}
+
+/// 一つの接続の購読
+struct Subscriptions {
+    state: &'static AppState,
+    rankings_config: web::Data<RankingsConfig>,
+    subscriptions: HashMap<String, Subscription>,
+}
+
+impl Subscriptions {
+    fn error(id: Option<String>, message: String) -> Vec<ServerMessage> {
+        vec![ServerMessage::Error { id, message }]
+    }
+
+    /// クライアントからのメッセージを処理し、返信するメッセージを返す。
+    async fn handle_client_message(&mut self, text: &str) -> Vec<ServerMessage> {
+        let message = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(message) => message,
+            Err(e) => return Self::error(None, format!("invalid message: {e}")),
+        };
+
+        match message {
+            ClientMessage::Subscribe {
+                id,
+                kind,
+                time_range,
+                target,
+            } => {
+                let kind_specifier = kind.as_deref().unwrap_or("break");
+                let Ok(kind) = AttributionKind::from_str(kind_specifier) else {
+                    return Self::error(
+                        Some(id),
+                        format!("{kind_specifier} is not a recognized attribution specifier"),
+                    );
+                };
+                let time_range_specifier = time_range.as_deref().unwrap_or("all");
+                let Ok(time_range) = AggregationTimeRange::from_str(time_range_specifier) else {
+                    return Self::error(
+                        Some(id),
+                        format!("{time_range_specifier} is not a recognized duration specifier."),
+                    );
+                };
+                if let Target::Top(limit) = target {
+                    if limit > self.rankings_config.for_kind(kind).max_limit() {
+                        return Self::error(Some(id), format!("{limit} is too large for a limit"));
+                    }
+                }
+                if self.subscriptions.contains_key(&id) {
+                    return Self::error(Some(id), "already subscribed".to_string());
+                }
+                if self.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                    return Self::error(
+                        Some(id),
+                        format!("cannot subscribe to more than {MAX_SUBSCRIPTIONS} targets"),
+                    );
+                }
+
+                let mut subscription = Subscription {
+                    kind,
+                    time_range,
+                    target,
+                    sent: HashMap::new(),
+                };
+                let initial = subscription.refresh(&id, self.state, true).await;
+                self.subscriptions.insert(id.clone(), subscription);
+
+                [Some(ServerMessage::Subscribed { id }), initial]
+                    .into_iter()
+                    .flatten()
+                    .collect()
+            }
+            ClientMessage::Unsubscribe { id } => match self.subscriptions.remove(&id) {
+                Some(_) => vec![ServerMessage::Unsubscribed { id }],
+                None => Self::error(Some(id), "not subscribed".to_string()),
+            },
+        }
+    }
+
+    /// ランキングが更新された後に送るメッセージを返す。
+    ///
+    /// `update` が `None` の場合は、どのランキングが更新されたかわからないものとしてすべての購読を調べる。
+    /// 匿名の一覧が変わった後にも `None` で呼ぶ。
+    async fn handle_update(&mut self, update: Option<RankingUpdate>) -> Vec<ServerMessage> {
+        let mut messages = vec![];
+
+        for (id, subscription) in &mut self.subscriptions {
+            if update.is_some_and(|update| !subscription.is_interested_in(update)) {
+                continue;
+            }
+            if let Some(message) = subscription.refresh(id, self.state, false).await {
+                messages.push(message);
+            }
+        }
+
+        messages
+    }
+}
+
+async fn send_all(session: &mut Session, messages: Vec<ServerMessage>) -> Result<(), ()> {
+    for message in messages {
+        let text = serde_json::to_string(&message).map_err(|_| ())?;
+        // 送信待ちのメッセージが溜まっている間は、ここで待つ
+        session.text(text).await.map_err(|_| ())?;
+    }
+    Ok(())
+}
+
+async fn run_session(
+    mut subscriptions: Subscriptions,
+    mut session: Session,
+    mut messages: MessageStream,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut updates = subscriptions.state.subscribe_ranking_updates();
+    let mut privacy_list_changes = subscriptions.state.privacy_list.subscribe_changes();
+    let mut heartbeat =
+        tokio::time::interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+    let mut last_pong = Instant::now();
+
+    let close_reason = loop {
+        let replies = tokio::select! {
+            message = messages.recv() => match message {
+                Some(Ok(Message::Text(text))) => subscriptions.handle_client_message(&text).await,
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Some(Ok(Message::Pong(_))) => {
+                    last_pong = Instant::now();
+                    continue;
+                }
+                Some(Ok(Message::Close(reason))) => break reason,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    debug!("WebSocket protocol error: {e}");
+                    break Some(CloseCode::Protocol.into());
+                }
+                None => break None,
+            },
+            update = updates.recv() => match update {
+                Ok(update) => subscriptions.handle_update(Some(update)).await,
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("WebSocket session lagged behind by {skipped} ranking updates");
+                    subscriptions.handle_update(None).await
+                }
+                Err(RecvError::Closed) => break Some(CloseCode::Away.into()),
+            },
+            Ok(()) = privacy_list_changes.changed() => subscriptions.handle_update(None).await,
+            _ = heartbeat.tick() => {
+                if last_pong.elapsed() > HEARTBEAT_INTERVAL * 2 {
+                    debug!("Closing WebSocket session that stopped responding to pings");
+                    break None;
+                }
+                if session.ping(b"").await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            () = shutdown_requested(&mut shutdown) => break Some(CloseCode::Away.into()),
+        };
+
+        if send_all(&mut session, replies).await.is_err() {
+            return;
+        }
+    };
+
+    // 送るものがないままセッションを破棄すると、接続が閉じられずに残ってしまうので、
+    // クライアントが切断した後でも閉じるフレームを送る
+    let _ = session.close(close_reason).await;
+}
+
+/// WebSocket で接続し、一つの接続で複数のランキングやプレーヤーを購読する。
+#[utoipa::path(
+    get,
+    path = "/ws",
+    responses(
+        (status = 101, description = "WebSocket に切り替わる"),
+        (status = 400, description = "WebSocket のハンドシェイクではない"),
+    )
+)]
+#[allow(clippy::future_not_send)]
+#[actix_web::get("/ws")]
+pub async fn websocket(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<&'static AppState>,
+    rankings_config: web::Data<RankingsConfig>,
+    shutdown: web::Data<watch::Receiver<bool>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+
+    let subscriptions = Subscriptions {
+        state: data.get_ref(),
+        rankings_config,
+        subscriptions: HashMap::new(),
+    };
+    actix_web::rt::spawn(run_session(
+        subscriptions,
+        session,
+        messages,
+        shutdown.get_ref().clone(),
+    ));
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::{rehydrate_kind, AllAttributionRecordProviders, AppState};
+    use crate::config::RankingsConfig;
+    use crate::handlers::websocket::Subscriptions;
+    use crate::models::{
+        AggregatedPlayerAttribution, AggregationTimeRange, AttributionKind, AttributionRecord,
+        AttributionRecordProvider,
+    };
+    use crate::test_fixtures::records_of;
+    use actix_web::web::Data;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use uuid::Uuid;
+
+    /// 呼ばれるたびに二人目のプレーヤーの値が 100 ずつ増える
+    struct GrowingProvider(AtomicU64);
+
+    #[async_trait]
+    impl<Attribution: AggregatedPlayerAttribution + Send + 'static>
+        AttributionRecordProvider<Attribution> for GrowingProvider
+    {
+        async fn get_all_attribution_records(
+            &self,
+            _time_range: AggregationTimeRange,
+        ) -> Result<Vec<AttributionRecord<Attribution>>> {
+            let growing = self.0.fetch_add(100, Ordering::SeqCst);
+            Ok(records_of([(1, 250), (2, growing)]))
+        }
+    }
+
+    async fn send(subscriptions: &mut Subscriptions, text: &str) -> Value {
+        serde_json::to_value(subscriptions.handle_client_message(text).await).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn send_diffs_and_milestones_after_rehydration() {
+        let state: &'static AppState = Box::leak(Box::default());
+        let providers = AllAttributionRecordProviders {
+            break_count_provider: Box::new(GrowingProvider(AtomicU64::new(100))),
+            build_count_provider: Box::new(GrowingProvider(AtomicU64::new(100))),
+            play_ticks_provider: Box::new(GrowingProvider(AtomicU64::new(100))),
+            vote_count_provider: Box::new(GrowingProvider(AtomicU64::new(100))),
+        };
+        let rehydrate = || async {
+            rehydrate_kind(
+                AttributionKind::Break,
+                Some(AggregationTimeRange::All),
+                state,
+                &providers,
+                &RankingsConfig::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        };
+        let mut subscriptions = Subscriptions {
+            state,
+            rankings_config: Data::new(RankingsConfig::default()),
+            subscriptions: HashMap::new(),
+        };
+        let names = |message: &Value| {
+            message["updated"].as_array().map(|records| {
+                records
+                    .iter()
+                    .map(|r| r["player"]["name"].clone())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        rehydrate().await;
+        let replies = send(
+            &mut subscriptions,
+            r#"{"op": "subscribe", "id": "top", "top": 1}"#,
+        )
+        .await;
+        assert_eq!(replies[0], json!({ "event": "subscribed", "id": "top" }));
+        assert_eq!(names(&replies[1]), Some(vec![json!("player1")]));
+        let replies = send(
+            &mut subscriptions,
+            r#"{"op": "subscribe", "id": "m", "type": "break", "milestone": 300}"#,
+        )
+        .await;
+        assert_eq!(replies, json!([{ "event": "subscribed", "id": "m" }]));
+        let replies = send(
+            &mut subscriptions,
+            r#"{"op": "subscribe", "id": "x", "type": "dig", "top": 1}"#,
+        )
+        .await;
+        assert_eq!(replies[0]["event"], "error");
+
+        // player2: 200 (2 位)
+        rehydrate().await;
+        assert_eq!(subscriptions.handle_update(None).await.len(), 0);
+
+        // player2: 300 (1 位) で閾値に達する
+        rehydrate().await;
+        let mut messages = serde_json::to_value(subscriptions.handle_update(None).await).unwrap();
+        messages
+            .as_array_mut()
+            .unwrap()
+            .sort_by_key(|m| m["id"].to_string());
+        assert_eq!(messages[0]["event"], "milestone");
+        assert_eq!(messages[0]["reached"][0]["player"]["name"], "player2");
+        assert_eq!(names(&messages[1]), Some(vec![json!("player2")]));
+        assert_eq!(messages[1]["removed"], json!([Uuid::from_u128(1)]));
+
+        let replies = send(&mut subscriptions, r#"{"op": "unsubscribe", "id": "top"}"#).await;
+        assert_eq!(replies, json!([{ "event": "unsubscribed", "id": "top" }]));
+    }
+
+    #[actix_web::test]
+    async fn resend_records_after_privacy_list_changes() {
+        let state: &'static AppState = Box::leak(Box::default());
+        let providers = AllAttributionRecordProviders {
+            break_count_provider: Box::new(GrowingProvider(AtomicU64::new(100))),
+            build_count_provider: Box::new(GrowingProvider(AtomicU64::new(100))),
+            play_ticks_provider: Box::new(GrowingProvider(AtomicU64::new(100))),
+            vote_count_provider: Box::new(GrowingProvider(AtomicU64::new(100))),
+        };
+        rehydrate_kind(
+            AttributionKind::Break,
+            Some(AggregationTimeRange::All),
+            state,
+            &providers,
+            &RankingsConfig::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let mut subscriptions = Subscriptions {
+            state,
+            rankings_config: Data::new(RankingsConfig::default()),
+            subscriptions: HashMap::new(),
+        };
+        send(
+            &mut subscriptions,
+            r#"{"op": "subscribe", "id": "top", "top": 2}"#,
+        )
+        .await;
+
+        let player1 = Uuid::from_u128(1);
+        let changes = state.privacy_list.subscribe_changes();
+        state.privacy_list.insert(player1);
+        assert!(changes.has_changed().unwrap());
+
+        let messages = serde_json::to_value(subscriptions.handle_update(None).await).unwrap();
+        let pseudonym = state.privacy_list.pseudonym(player1);
+        assert_eq!(messages[0]["event"], "diff");
+        assert_eq!(
+            messages[0]["updated"][0]["player"],
+            json!({ "uuid": pseudonym, "name": "匿名のプレーヤー", "last_quit": "1970-01-01T00:00:00Z" })
+        );
+        assert_eq!(messages[0]["updated"][1]["player"]["name"], "player2");
+        assert_eq!(messages[0]["removed"], json!([player1]));
+
+        state.privacy_list.remove(player1);
+        let messages = serde_json::to_value(subscriptions.handle_update(None).await).unwrap();
+        assert_eq!(messages[0]["updated"][0]["player"]["uuid"], json!(player1));
+        assert_eq!(messages[0]["removed"], json!([pseudonym]));
+    }
+}
@@ -1,4 +1,14 @@
 pub mod app_models;
+pub mod auth;
+pub mod cli;
 pub mod config;
 pub mod handlers;
+pub mod logging;
 pub mod models;
+pub mod rate_limit;
+pub mod skin_store;
+pub mod snapshot_store;
+#[cfg(test)]
+mod test_fixtures;
+pub mod tls;
+pub mod webhooks;
@@ -0,0 +1,265 @@
+use crate::config::{LogFormat, LoggingConfig};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use anyhow::{Context, Result};
+use fern::colors::ColoredLevelConfig;
+use fern::FormatCallback;
+use log::Record;
+use std::ffi::OsString;
+use std::fmt::Arguments;
+use std::fs::{self, File, OpenOptions};
+use std::future::{ready, Future, Ready};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use uuid::Uuid;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// リクエスト ID を入れるヘッダー
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// 新しいリクエスト ID を作る。
+pub fn new_request_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// `future` の中で出力されるログに `request_id` を付ける。
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+fn formatter(
+    format: LogFormat,
+    colored: bool,
+) -> impl Fn(FormatCallback, &Arguments, &Record) + Sync + Send + 'static {
+    let colors = ColoredLevelConfig::new();
+
+    move |out, message, record| match format {
+        LogFormat::Text => {
+            let request_id = current_request_id()
+                .map(|request_id| format!("[{request_id}]"))
+                .unwrap_or_default();
+            let timestamp = chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]");
+
+            if colored {
+                out.finish(format_args!(
+                    "{timestamp}[{}][{}]{request_id} {message}",
+                    record.target(),
+                    colors.color(record.level()),
+                ));
+            } else {
+                out.finish(format_args!(
+                    "{timestamp}[{}][{}]{request_id} {message}",
+                    record.target(),
+                    record.level(),
+                ));
+            }
+        }
+        LogFormat::Json => {
+            let mut line = serde_json::json!({
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": message.to_string(),
+            });
+            if let Some(request_id) = current_request_id() {
+                line["request_id"] = request_id.into();
+            }
+            out.finish(format_args!("{line}"));
+        }
+    }
+}
+
+/// `config` に従ってログの出力先を設定する。
+pub fn setup(config: &LoggingConfig) -> Result<()> {
+    let mut dispatch = fern::Dispatch::new().level(config.level);
+    for filter in &config.filters {
+        dispatch = dispatch.level_for(filter.target.clone(), filter.level);
+    }
+
+    dispatch = dispatch.chain(
+        fern::Dispatch::new()
+            .format(formatter(config.format, true))
+            .chain(io::stdout()),
+    );
+
+    if let Some(path) = &config.file {
+        let file = RotatingFile::open(
+            PathBuf::from(path),
+            config.file_max_bytes(),
+            config.file_max_backups(),
+        )
+        .with_context(|| format!("failed to open log file {path}"))?;
+
+        dispatch = dispatch.chain(
+            fern::Dispatch::new()
+                .format(formatter(config.format, false))
+                .chain(Box::new(file) as Box<dyn Write + Send>),
+        );
+    }
+
+    dispatch.apply()?;
+    Ok(())
+}
+
+/// 大きさが上限を超えると、 `{path}.1`, `{path}.2`, ... と古いファイルをずらして新しいファイルに切り替えるログファイル
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_backups: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_backups: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_bytes,
+            max_backups,
+        })
+    }
+
+    fn backup_path(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{index}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_backups).rev() {
+            let backup = self.backup_path(index);
+            if backup.exists() {
+                fs::rename(&backup, self.backup_path(index + 1))?;
+            }
+        }
+        if self.max_backups > 0 {
+            fs::rename(&self.path, self.backup_path(1))?;
+        }
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    /// ログの一行が書き終わるたびに呼ばれるので、行の途中でファイルが切り替わることはない。
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.size >= self.max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+/// リクエストごとに ID を割り当て、処理中に出力されるログとレスポンスの `X-Request-Id` ヘッダーに付けるミドルウェア。
+///
+/// リクエストに `X-Request-Id` ヘッダーが付いていて、それが ID として妥当であればその値を使う。
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+fn is_acceptable_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 64
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_acceptable_request_id(value))
+            .map_or_else(new_request_id, ToString::to_string);
+
+        let response = REQUEST_ID.sync_scope(request_id.clone(), || self.service.call(request));
+
+        Box::pin(with_request_id(request_id.clone(), async move {
+            let mut response = response.await?;
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(response)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::logging::RotatingFile;
+    use std::io::Write;
+
+    #[test]
+    fn rotate_log_file_when_it_grows_too_large() {
+        let directory = std::env::temp_dir().join(format!("rotating-log-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("bff.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first line\n", "second line\n", "third line\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+            file.flush().unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(directory.join(name)).unwrap();
+        assert_eq!(read("bff.log"), "fourth\n");
+        assert_eq!(read("bff.log.1"), "third line\n");
+        assert_eq!(read("bff.log.2"), "second line\n");
+        assert!(!directory.join("bff.log.3").exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
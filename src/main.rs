@@ -1,76 +1,391 @@
 #![deny(clippy::all, clippy::cargo)]
 #![warn(clippy::nursery, clippy::pedantic)]
-#![allow(clippy::cargo_common_metadata)]
-
-use actix_web::web::Data;
-use actix_web::{web, App, HttpServer};
-use anyhow::{Context, Result};
-use log::{info, trace, warn};
-use once_cell::sync::Lazy;
-use seichi_ranking_bff::app_models::{AllAttributionRecordProviders, AppState};
+#![allow(clippy::cargo_common_metadata, clippy::multiple_crate_versions)]
+
+use actix_cors::Cors;
+use actix_web::dev::Server;
+use actix_web::http::header::{self, HeaderName};
+use actix_web::middleware::{Compress, Condition, Logger};
+use actix_web::web::{self, Data};
+use actix_web::{App, HttpServer};
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use log::{error, info, trace, warn};
+use seichi_ranking_bff::app_models::{
+    AllAttributionRecordProviders, AppState, LazyAttributionRecordProviders, Rehydrator,
+};
+use seichi_ranking_bff::auth::{ApiKeyAuthentication, ApiKeyUsage, API_KEY_HEADER};
+use seichi_ranking_bff::cli::{self, Cli, Command, RankingSelection};
+use seichi_ranking_bff::logging::{self, RequestId, REQUEST_ID_HEADER};
+use seichi_ranking_bff::rate_limit::{RateLimit, RateLimiter};
+use seichi_ranking_bff::skin_store::SkinStore;
+use seichi_ranking_bff::snapshot_store::RankingSnapshotStore;
+use seichi_ranking_bff::tls::{self, ReloadableCertificateResolver};
 use seichi_ranking_bff::{
     app_models,
-    config::{Config, FromEnv},
-    handlers::{ranking::player_rank, ranking::ranking},
+    config::{Config, CorsConfig, HttpConfig, SnapshotConfig, WebhookConfig},
+    handlers::{
+        configure_api, graphql::build_schema, images::Rasterizer,
+        leaderboard::LeaderboardImageCache, movers::MoversBaselineCache, openapi::openapi_json,
+    },
+    webhooks,
 };
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
-fn setup_logger() -> Result<(), fern::InitError> {
-    use fern::colors::ColoredLevelConfig;
-    let colors = ColoredLevelConfig::new();
-
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                colors.color(record.level()),
-                message
-            ));
-        })
-        .level(log::LevelFilter::Trace)
-        .chain(std::io::stdout())
-        .chain(fern::log_file("output.log")?)
-        .apply()?;
-    Ok(())
+/// ランキングのデータを読むプロバイダ。データベースから読むプロバイダはまだ実装されていないので、エラーを返す。
+fn attribution_record_providers() -> Result<AllAttributionRecordProviders> {
+    bail!(
+        "no attribution record provider is implemented; rankings cannot be read from the database"
+    )
 }
 
-fn attribution_record_providers() -> AllAttributionRecordProviders {
-    todo!()
-}
+/// [`Logger`] の既定の形式に、リクエスト ID を加えたもの
+const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#;
 
-static APP_STATE_DATA: Lazy<Data<AppState>> = Lazy::new(|| web::Data::new(AppState::default()));
+static APP_STATE: LazyLock<AppState> = LazyLock::new(AppState::default);
+
+/// データベースに接続するのは実際にランキングを更新するときでよいので、サーバーの起動時には作らない
+static PROVIDERS: LazyAttributionRecordProviders =
+    LazyLock::new(|| attribution_record_providers().unwrap());
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    println!("starting");
-    if let Err(err) = setup_logger().context("failed to setup logger") {
-        eprintln!("failed to initialize logger: {err:?}");
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(cli.config.as_deref()).await,
+        Command::CheckConfig => check_config(cli.config.as_deref()),
+        Command::DumpRanking(selection) => dump_ranking(cli.config.as_deref(), &selection).await,
+        Command::LoadSnapshot { path, selection } => {
+            let state = AppState::default();
+            let saved_at = state.restore_from_file(&path).await?;
+            eprintln!("state file saved at {saved_at}");
+            cli::write_ranking(&state, &selection, &mut std::io::stdout()).await
+        }
     }
+}
+
+fn check_config(config_file: Option<&Path>) -> Result<()> {
+    let config = Config::load(config_file)?;
+    println!("config is valid");
+
+    cli::check_database_reachable(&config.database_authorization)?;
+    println!(
+        "database at {}:{} is reachable",
+        config.database_authorization.host, config.database_authorization.port.0
+    );
+    Ok(())
+}
+
+async fn dump_ranking(config_file: Option<&Path>, selection: &RankingSelection) -> Result<()> {
+    let config = Config::load(config_file)?;
+    let state = AppState::default();
+    state
+        .privacy_list
+        .extend(config.privacy_config.anonymized_players);
+    let providers = attribution_record_providers()?;
+
+    app_models::rehydrate_kind(
+        selection.kind,
+        Some(selection.time_range),
+        &state,
+        &providers,
+        &config.rankings_config,
+        None,
+    )
+    .await?;
+
+    cli::write_ranking(&state, selection, &mut std::io::stdout()).await
+}
+
+/// `config` で許可されたオリジンからのブラウザのリクエストを許可する [`Cors`] を作る。
+fn cors(config: &CorsConfig) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            HeaderName::from_static(API_KEY_HEADER),
+            HeaderName::from_static(REQUEST_ID_HEADER),
+        ])
+        .expose_headers([
+            header::RETRY_AFTER,
+            HeaderName::from_static(REQUEST_ID_HEADER),
+        ])
+        .max_age(config.max_age_secs());
 
-    trace!("Reading config...");
-    let config = Config::from_env()?;
+    cors = if config.allowed_methods.is_empty() {
+        cors.allowed_methods(["GET"])
+    } else {
+        cors.allowed_methods(config.allowed_methods.iter().map(String::as_str))
+    };
+
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        config
+            .allowed_origins
+            .iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin))
+    }
+}
+
+fn open_snapshot_store(config: &SnapshotConfig) -> Result<Option<Data<RankingSnapshotStore>>> {
+    let store = match &config.path {
+        Some(path) => {
+            trace!("Opening snapshot store at {path}");
+            let minimum_interval = chrono::Duration::from_std(config.interval())
+                .context("snapshot interval is too large")?;
+            let retention = config
+                .retention()
+                .map(chrono::Duration::from_std)
+                .transpose()
+                .context("snapshot retention is too long")?;
+            Some(Data::new(RankingSnapshotStore::open(
+                path,
+                minimum_interval,
+                retention,
+            )?))
+        }
+        None => None,
+    };
+
+    Ok(store)
+}
+
+async fn serve(config_file: Option<&Path>) -> Result<()> {
+    let config = Config::load(config_file)?;
+    logging::setup(&config.logging_config).context("failed to setup logger")?;
+    info!("starting");
+    let rankings_config = Data::new(config.rankings_config);
+    let api_keys = Arc::new(config.api_keys_config.keys);
+    let api_key_usage = Arc::new(ApiKeyUsage::new(&api_keys));
+    let rate_limiter =
+        RateLimiter::from_config(&config.rate_limit_config, api_keys.clone()).map(Arc::new);
+    let skin_store = Data::new(SkinStore::new(
+        config.image_config.skin_dir.as_ref().map(PathBuf::from),
+    ));
+    let rasterizer = Data::new(Rasterizer::new(&config.image_config));
+    let leaderboard_image_cache = Data::new(LeaderboardImageCache::default());
+    let movers_baseline_cache = Data::new(MoversBaselineCache::default());
+    APP_STATE
+        .privacy_list
+        .extend(config.privacy_config.anonymized_players);
+    let graphql_schema = Data::new(build_schema(
+        &APP_STATE,
+        rankings_config.clone(),
+        &config.graphql_config,
+    ));
+
+    let snapshot_store = open_snapshot_store(&config.snapshot_config)?;
+
+    let state_file = config
+        .snapshot_config
+        .state_file
+        .as_ref()
+        .map(PathBuf::from);
+    restore_state(state_file.as_deref()).await;
+
+    let shutdown_timeout = config.http_config.shutdown_timeout();
+    let compression_enabled = config.http_config.compression_enabled();
+    let cors_config = Arc::new(config.cors_config);
+
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let app_shutdown_receiver = Data::new(shutdown_receiver.clone());
 
     trace!("building HttpServer");
-    let http_server_future = HttpServer::new(|| {
-        App::new()
-            .app_data(&APP_STATE_DATA)
-            .wrap(actix_web::middleware::Logger::default())
-            .service(ranking)
-            .service(player_rank)
+    let rehydrator = Data::new(Rehydrator {
+        state: &APP_STATE,
+        providers: &PROVIDERS,
+        rankings_config: rankings_config.clone().into_inner(),
+        snapshot_store: snapshot_store.clone().map(Data::into_inner),
+        state_file,
+    });
+
+    let app_rehydrator = rehydrator.clone();
+    let app_snapshot_store = snapshot_store.clone();
+    let http_server = HttpServer::new(move || {
+        let app = App::new()
+            .app_data(Data::new(&*APP_STATE))
+            .app_data(rankings_config.clone())
+            .app_data(Data::from(api_key_usage.clone()))
+            .app_data(app_rehydrator.clone())
+            .app_data(graphql_schema.clone())
+            .app_data(skin_store.clone())
+            .app_data(rasterizer.clone())
+            .app_data(leaderboard_image_cache.clone())
+            .app_data(movers_baseline_cache.clone())
+            .app_data(app_shutdown_receiver.clone());
+        let app = match &app_snapshot_store {
+            Some(store) => app.app_data(store.clone()),
+            None => app,
+        };
+
+        app.wrap(ApiKeyAuthentication::new(
+            api_keys.clone(),
+            api_key_usage.clone(),
+        ))
+        .wrap(RateLimit::new(rate_limiter.clone()))
+        .wrap(RequestId)
+        .wrap(Condition::new(
+            !cors_config.allowed_origins.is_empty(),
+            cors(&cors_config),
+        ))
+        .wrap(Condition::new(compression_enabled, Compress::default()))
+        .wrap(Logger::new(ACCESS_LOG_FORMAT))
+        .service(web::scope("/v1").configure(configure_api))
+        .configure(configure_api)
+        .service(openapi_json)
     })
-    .bind(format!(
-        "{}:{}",
-        config.http_config.host, config.http_config.port.0
-    ))?
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs());
+
+    let address = format!("{}:{}", config.http_config.host, config.http_config.port.0);
+    let http_server_future = match tls_server_config(&config.http_config)? {
+        Some(server_config) => http_server.bind_rustls(address, server_config)?,
+        None => http_server.bind(address)?,
+    }
     .run();
 
-    tokio::spawn(async {
-        let providers = attribution_record_providers();
-        app_models::rehydration_process(&APP_STATE_DATA, providers).await;
+    let webhook_task = spawn_webhook_task(config.webhook_config, shutdown_receiver.clone());
+
+    let rehydration_task = tokio::spawn(async move {
+        app_models::rehydration_process(&rehydrator, shutdown_receiver, shutdown_timeout).await
     });
 
-    http_server_future.await.unwrap();
-    info!("stopped");
-    Ok(())
+    run_until_shutdown(
+        http_server_future,
+        shutdown_sender,
+        rehydration_task,
+        webhook_task,
+    )
+    .await
+}
+
+/// TLS が設定されていれば証明書を読み込み、 SIGHUP を受け取るたびに証明書を読み込み直すタスクを始める。
+fn tls_server_config(http_config: &HttpConfig) -> Result<Option<rustls::ServerConfig>> {
+    let Some(tls_config) = http_config.tls() else {
+        return Ok(None);
+    };
+
+    trace!("Loading TLS certificate");
+    let resolver = Arc::new(ReloadableCertificateResolver::load(
+        tls_config.cert_path,
+        tls_config.key_path,
+    )?);
+    let server_config = tls::server_config(&tls_config, resolver.clone())?;
+
+    tokio::spawn(async move {
+        if let Err(e) = tls::reload_on_sighup(resolver).await {
+            error!("Error waiting for SIGHUP: {e:?}");
+        }
+    });
+
+    Ok(Some(server_config))
+}
+
+/// 前回保存したランキングが `path` にあれば読み込む。読み込めなくても、空のランキングから始めればよいので失敗はしない。
+async fn restore_state(path: Option<&Path>) {
+    if let Some(path) = path.filter(|path| path.exists()) {
+        match APP_STATE.restore_from_file(path).await {
+            Ok(saved_at) => info!("Restored rankings saved at {saved_at} from state file"),
+            Err(e) => warn!("Failed to restore rankings from state file: {e:?}"),
+        }
+    }
+}
+
+/// 通知を送る URL が設定されていれば、ランキングの更新に応じて通知を送るタスクを始める。
+///
+/// 最初の更新を取りこぼさないよう、ランキングの更新を始める前に呼ぶ必要がある。
+fn spawn_webhook_task(
+    config: WebhookConfig,
+    shutdown_receiver: watch::Receiver<bool>,
+) -> Option<JoinHandle<()>> {
+    if config.urls.is_empty() {
+        return None;
+    }
+
+    let updates = APP_STATE.subscribe_ranking_updates();
+    // HTTP クライアントはスレッド間で共有できないので、このスレッドで動かす
+    Some(actix_web::rt::spawn(async move {
+        webhooks::webhook_process(&APP_STATE, &config, updates, shutdown_receiver).await;
+    }))
+}
+
+/// HTTP サーバーが止まるかシグナルを受け取るまで待ち、処理中のリクエストとランキングの更新、送っている途中の通知を終わらせる。
+///
+/// HTTP サーバーがエラーで止まった場合や、ランキングを更新するタスクが失敗した場合はエラーを返す。
+async fn run_until_shutdown(
+    http_server_future: Server,
+    shutdown_sender: watch::Sender<bool>,
+    rehydration_task: JoinHandle<Result<()>>,
+    webhook_task: Option<JoinHandle<()>>,
+) -> Result<()> {
+    let server_handle = http_server_future.handle();
+    tokio::pin!(http_server_future);
+    let server_result = tokio::select! {
+        result = &mut http_server_future => result,
+        signal = shutdown_signal() => {
+            info!("Received {signal}; draining in-flight requests");
+            // 配信中のイベントストリームを閉じないと、接続が残ってサーバーが止まらない
+            let _ = shutdown_sender.send(true);
+            // 停止の完了は、サーバーのフューチャーを進めないと通知されない
+            tokio::join!(server_handle.stop(true), http_server_future).1
+        }
+    };
+
+    let _ = shutdown_sender.send(true);
+    let rehydration_result = match rehydration_task.await {
+        Ok(result) => result,
+        Err(e) => Err(e).context("rehydration task panicked"),
+    };
+    if let Some(webhook_task) = webhook_task {
+        if let Err(e) = webhook_task.await {
+            error!("Webhook task panicked: {e:?}");
+        }
+    }
+
+    let result = server_result
+        .context("HTTP server stopped with an error")
+        .and(rehydration_result);
+    match &result {
+        Ok(()) => info!("stopped"),
+        Err(e) => error!("stopped with an error: {e:?}"),
+    }
+    log::logger().flush();
+
+    result
+}
+
+/// SIGTERM か SIGINT (Windows では Ctrl-C) を受け取るまで待ち、受け取ったシグナルの名前を返す。
+#[cfg(unix)]
+async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut terminate), Ok(mut interrupt)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        error!(
+            "Error listening for shutdown signals; the server can only be stopped by killing it"
+        );
+        return std::future::pending().await;
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> &'static str {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Error listening for Ctrl-C; the server can only be stopped by killing it: {e:?}");
+        std::future::pending::<()>().await;
+    }
+    "Ctrl-C"
 }
@@ -1,61 +1,134 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::iter;
 use strum;
 use strum::{Display, EnumIter, EnumString};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Player {
     pub uuid: Uuid,
     pub name: String,
     pub last_quit: DateTime<Utc>,
 }
 
-#[derive(PartialEq, PartialOrd, Eq, Ord, Clone)]
+#[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Serialize, Deserialize)]
 pub struct BreakCount(u64);
 
-#[derive(PartialEq, PartialOrd, Eq, Ord, Clone)]
+#[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Serialize, Deserialize)]
 pub struct BuildCount(u64);
 
-#[derive(PartialEq, PartialOrd, Eq, Ord, Clone)]
+#[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Serialize, Deserialize)]
 pub struct PlayTicks(u64);
 
-#[derive(PartialEq, PartialOrd, Eq, Ord, Clone)]
+#[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Serialize, Deserialize)]
 pub struct VoteCount(u64);
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, EnumString, EnumIter, Display, ToSchema)]
+pub enum AttributionKind {
+    #[strum(serialize = "break")]
+    #[schema(rename = "break")]
+    Break,
+    #[strum(serialize = "build")]
+    #[schema(rename = "build")]
+    Build,
+    #[strum(serialize = "play_ticks")]
+    #[schema(rename = "play_ticks")]
+    PlayTicks,
+    #[strum(serialize = "vote_count")]
+    #[schema(rename = "vote_count")]
+    VoteCount,
+}
+
+/// ランキングの値の単位
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributionUnit {
+    /// 回数や量 (整地量、建築量、投票数)
+    Count,
+    /// ゲーム内の tick 数。 [`AttributionUnit::TICKS_PER_SECOND`] tick が 1 秒にあたる
+    Tick,
+}
+
+impl AttributionUnit {
+    pub const TICKS_PER_SECOND: u64 = 20;
+}
+
 pub trait AggregatedPlayerAttribution: Ord + Clone {
+    const KIND: AttributionKind;
+
+    const UNIT: AttributionUnit;
+
     fn raw_u64_data(&self) -> u64;
+
+    fn from_raw_u64_data(data: u64) -> Self;
 }
 
 macro_rules! impl_aggregated_player_attribution_for_u64_tuple {
-    ($attribution_struct:ident) => {
+    ($attribution_struct:ident, $kind:expr, $unit:expr) => {
         impl AggregatedPlayerAttribution for $attribution_struct {
+            const KIND: AttributionKind = $kind;
+
+            const UNIT: AttributionUnit = $unit;
+
             fn raw_u64_data(&self) -> u64 {
                 self.0
             }
+
+            fn from_raw_u64_data(data: u64) -> Self {
+                Self(data)
+            }
         }
     };
 }
 
-impl_aggregated_player_attribution_for_u64_tuple!(BreakCount);
-impl_aggregated_player_attribution_for_u64_tuple!(BuildCount);
-impl_aggregated_player_attribution_for_u64_tuple!(PlayTicks);
-impl_aggregated_player_attribution_for_u64_tuple!(VoteCount);
+impl_aggregated_player_attribution_for_u64_tuple!(
+    BreakCount,
+    AttributionKind::Break,
+    AttributionUnit::Count
+);
+impl_aggregated_player_attribution_for_u64_tuple!(
+    BuildCount,
+    AttributionKind::Build,
+    AttributionUnit::Count
+);
+impl_aggregated_player_attribution_for_u64_tuple!(
+    PlayTicks,
+    AttributionKind::PlayTicks,
+    AttributionUnit::Tick
+);
+impl_aggregated_player_attribution_for_u64_tuple!(
+    VoteCount,
+    AttributionKind::VoteCount,
+    AttributionUnit::Count
+);
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AttributionRecord<Attribution: AggregatedPlayerAttribution> {
     pub player: Player,
     pub attribution: Attribution,
 }
 
-#[derive(Clone)]
+/// 前回ランキングが更新されたときの順位と値
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PreviousRankedRecord<Attribution: AggregatedPlayerAttribution> {
+    pub rank: u32,
+    pub attribution: Attribution,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RankedAttributionRecord<Attribution: AggregatedPlayerAttribution> {
     pub rank: u32,
     pub attribution_record: AttributionRecord<Attribution>,
+    /// 更新前のランキングにプレーヤーが含まれていなかった場合は `None`
+    pub previous: Option<PreviousRankedRecord<Attribution>>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Ranking<Attribution: AggregatedPlayerAttribution> {
     /// 不変条件: `sorted_ranked_records` は「順位」が与えられており、次の条件を常に満たす。
     ///  - 任意の添え字 `i` について、
@@ -63,6 +136,10 @@ pub struct Ranking<Attribution: AggregatedPlayerAttribution> {
     ///    すべての `r ≤ j ≤ i` について、
     ///    `sorted_ranked_records[j].rank.0 == r + 1`
     sorted_ranked_records: Vec<RankedAttributionRecord<Attribution>>,
+    /// ランキングが更新されるたびに増える世代番号。
+    /// 世代番号が同じであれば中身も同じなので、ランキングから作ったものを使い回す目印にできる。
+    #[serde(default)]
+    generation: u64,
 }
 
 pub struct RankingSlice<Attribution: AggregatedPlayerAttribution>(
@@ -73,6 +150,7 @@ impl<Attribution: AggregatedPlayerAttribution + Clone> Default for Ranking<Attri
     fn default() -> Self {
         Ranking {
             sorted_ranked_records: vec![],
+            generation: 0,
         }
     }
 }
@@ -85,6 +163,22 @@ impl<Attribution: AggregatedPlayerAttribution + Clone> Ranking<Attribution> {
             previous_item_rank: u32,
         }
 
+        self.generation += 1;
+
+        let previous_records = self
+            .sorted_ranked_records
+            .drain(..)
+            .map(|r| {
+                (
+                    r.attribution_record.player.uuid,
+                    PreviousRankedRecord {
+                        rank: r.rank,
+                        attribution: r.attribution_record.attribution,
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
         let mut records = records;
         records.sort_by_key(|ar| ar.attribution.clone());
         records.reverse();
@@ -100,10 +194,11 @@ impl<Attribution: AggregatedPlayerAttribution + Clone> Ranking<Attribution> {
         let first_ranked_record = RankedAttributionRecord {
             rank: 1,
             attribution_record: first_record.clone(),
+            previous: previous_records.get(&first_record.player.uuid).cloned(),
         };
 
         let initial_scan_state = ScanState {
-            next_item_index: 0,
+            next_item_index: 1,
             previous_attribution: first_record.attribution.clone(),
             previous_item_rank: 1,
         };
@@ -112,13 +207,14 @@ impl<Attribution: AggregatedPlayerAttribution + Clone> Ranking<Attribution> {
             let next_rank = if st.previous_attribution == record.attribution {
                 st.previous_item_rank
             } else {
-                assert!(st.previous_attribution < record.attribution);
+                assert!(st.previous_attribution > record.attribution);
                 (st.next_item_index as u32) + 1
             };
 
             let next_ranked_record = RankedAttributionRecord {
                 rank: next_rank,
                 attribution_record: record.clone(),
+                previous: previous_records.get(&record.player.uuid).cloned(),
             };
 
             st.next_item_index += 1;
@@ -134,7 +230,67 @@ impl<Attribution: AggregatedPlayerAttribution + Clone> Ranking<Attribution> {
     }
 
     pub fn paginate(&self, offset: usize, limit: usize) -> RankingSlice<Attribution> {
-        RankingSlice(self.sorted_ranked_records[offset..(offset + limit)].to_vec())
+        let end = self
+            .sorted_ranked_records
+            .len()
+            .min(offset.saturating_add(limit));
+        let start = offset.min(end);
+
+        RankingSlice(self.sorted_ranked_records[start..end].to_vec())
+    }
+
+    pub fn ranked_records(&self) -> &[RankedAttributionRecord<Attribution>] {
+        &self.sorted_ranked_records
+    }
+
+    pub const fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// `baseline_of` が返す過去の順位・値と比べて、 `criterion` が最も伸びたプレーヤーを `limit` 人まで返す。
+    ///
+    /// 返されるレコードの `previous` には、比較に用いた過去の順位・値が入る。
+    /// 過去の順位・値が無いプレーヤーや、 `criterion` が伸びていないプレーヤーは含まれない。
+    pub fn top_movers(
+        &self,
+        criterion: MoverCriterion,
+        limit: usize,
+        baseline_of: impl Fn(
+            &RankedAttributionRecord<Attribution>,
+        ) -> Option<PreviousRankedRecord<Attribution>>,
+    ) -> Vec<RankedAttributionRecord<Attribution>> {
+        let mut movers = self
+            .sorted_ranked_records
+            .iter()
+            .filter_map(|record| {
+                let baseline = baseline_of(record)?;
+                let gain = match criterion {
+                    MoverCriterion::Rank => u64::from(baseline.rank.checked_sub(record.rank)?),
+                    MoverCriterion::Value => record
+                        .attribution_record
+                        .attribution
+                        .raw_u64_data()
+                        .checked_sub(baseline.attribution.raw_u64_data())?,
+                };
+
+                (gain > 0).then(|| {
+                    let mut record = record.clone();
+                    record.previous = Some(baseline);
+                    (gain, record)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // 伸びが同じ場合は現在の順位が高い方を優先する
+        movers.sort_by(|(gain_a, record_a), (gain_b, record_b)| {
+            gain_b.cmp(gain_a).then(record_a.rank.cmp(&record_b.rank))
+        });
+
+        movers
+            .into_iter()
+            .take(limit)
+            .map(|(_, record)| record)
+            .collect()
     }
 
     pub fn record_with_uuid(&self, uuid: Uuid) -> Option<RankedAttributionRecord<Attribution>> {
@@ -145,21 +301,35 @@ impl<Attribution: AggregatedPlayerAttribution + Clone> Ranking<Attribution> {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, EnumString, EnumIter, Display)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, EnumString, EnumIter, Display, ToSchema)]
 #[strum(serialize_all = "snake_case")]
 pub enum AggregationTimeRange {
     #[strum(serialize = "all")]
+    #[schema(rename = "all")]
     All,
     #[strum(serialize = "year")]
+    #[schema(rename = "year")]
     LastOneYear,
     #[strum(serialize = "month")]
+    #[schema(rename = "month")]
     LastOneMonth,
     #[strum(serialize = "week")]
+    #[schema(rename = "week")]
     LastOneWeek,
     #[strum(serialize = "day")]
+    #[schema(rename = "day")]
     LastOneDay,
 }
 
+/// 順位の上昇幅と値の増加量のどちらで「伸び」を測るか
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumString, Display)]
+pub enum MoverCriterion {
+    #[strum(serialize = "rank")]
+    Rank,
+    #[strum(serialize = "value")]
+    Value,
+}
+
 #[async_trait]
 pub trait AttributionRecordProvider<Attribution: AggregatedPlayerAttribution> {
     async fn get_all_attribution_records(
@@ -167,3 +337,106 @@ pub trait AttributionRecordProvider<Attribution: AggregatedPlayerAttribution> {
         time_range: AggregationTimeRange,
     ) -> Result<Vec<AttributionRecord<Attribution>>>;
 }
+
+#[cfg(test)]
+mod test {
+    use crate::models::{AggregatedPlayerAttribution, BreakCount, MoverCriterion, Ranking};
+    use crate::test_fixtures::records_of;
+
+    #[test]
+    fn hydrate_record_set_remembers_previous_ranks() {
+        let mut ranking = Ranking::<BreakCount>::default();
+        ranking.hydrate_record_set(records_of([(1, 10), (2, 20), (3, 20)]));
+        ranking.hydrate_record_set(records_of([(1, 30), (2, 20), (4, 5)]));
+
+        let ranks = ranking
+            .ranked_records()
+            .iter()
+            .map(|r| {
+                (
+                    r.attribution_record.player.uuid.as_u128(),
+                    r.rank,
+                    r.previous
+                        .as_ref()
+                        .map(|p| (p.rank, p.attribution.raw_u64_data())),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            ranks,
+            vec![(1, 1, Some((3, 10))), (2, 2, Some((1, 20))), (4, 3, None)]
+        );
+        assert_eq!(ranking.generation(), 2);
+    }
+
+    #[test]
+    fn tied_records_share_a_rank_and_the_next_rank_skips_them() {
+        let mut ranking = Ranking::<BreakCount>::default();
+        ranking.hydrate_record_set(records_of([(1, 30), (2, 50), (3, 40), (4, 40), (5, 10)]));
+
+        let ranks = ranking
+            .ranked_records()
+            .iter()
+            .map(|r| r.rank)
+            .collect::<Vec<_>>();
+        assert_eq!(ranks, vec![1, 2, 2, 4, 5]);
+    }
+
+    #[test]
+    fn distinct_records_get_consecutive_ranks_in_descending_order() {
+        let mut ranking = Ranking::<BreakCount>::default();
+        ranking.hydrate_record_set(records_of([(1, 10), (2, 30), (3, 20), (4, 40)]));
+
+        let ranks_and_values = ranking
+            .ranked_records()
+            .iter()
+            .map(|r| (r.rank, r.attribution_record.attribution.raw_u64_data()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranks_and_values, vec![(1, 40), (2, 30), (3, 20), (4, 10)]);
+
+        ranking.hydrate_record_set(records_of([(1, 10)]));
+        assert_eq!(ranking.ranked_records()[0].rank, 1);
+        ranking.hydrate_record_set(vec![]);
+        assert!(ranking.ranked_records().is_empty());
+    }
+
+    #[test]
+    fn paginate_clamps_offsets_and_limits_past_the_end() {
+        let mut ranking = Ranking::<BreakCount>::default();
+        ranking.hydrate_record_set(records_of([(1, 40), (2, 30), (3, 20), (4, 10)]));
+
+        let uuids_of = |offset, limit| {
+            ranking
+                .paginate(offset, limit)
+                .0
+                .iter()
+                .map(|r| r.attribution_record.player.uuid.as_u128())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(uuids_of(1, 2), vec![2, 3]);
+        assert_eq!(uuids_of(2, 100), vec![3, 4]);
+        assert_eq!(uuids_of(4, 10), Vec::<u128>::new());
+        assert_eq!(uuids_of(10, 10), Vec::<u128>::new());
+        assert_eq!(uuids_of(1, usize::MAX), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn top_movers_are_sorted_by_gain() {
+        let mut ranking = Ranking::<BreakCount>::default();
+        ranking.hydrate_record_set(records_of([(1, 10), (2, 20), (3, 30), (4, 40)]));
+        ranking.hydrate_record_set(records_of([(1, 50), (2, 45), (3, 30), (4, 41), (5, 1)]));
+
+        let movers_by = |criterion| {
+            ranking
+                .top_movers(criterion, 10, |r| r.previous.clone())
+                .iter()
+                .map(|r| r.attribution_record.player.uuid.as_u128())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(movers_by(MoverCriterion::Rank), vec![1, 2]);
+        assert_eq!(movers_by(MoverCriterion::Value), vec![1, 2, 4]);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::auth::{find_api_key, presented_api_key};
+use crate::config::{ApiKey, RateLimitConfig};
+use actix_web::body::{BoxBody, EitherBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use log::warn;
+use qstring::QString;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// トークンバケツを分ける単位
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ClientKey {
+    ApiKey(String),
+    Address(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// クライアントごとのトークンバケツ
+pub struct TokenBuckets {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<ClientKey, Bucket>>,
+}
+
+impl TokenBuckets {
+    /// これを超える数のバケツを持つようになったら、満杯のバケツを捨てる
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            refill_per_sec,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// `client` のバケツから `cost` だけトークンを取り出す。
+    /// 足りなければトークンを取り出さず、十分に補充されるまでの時間を返す。
+    pub fn take(&self, client: ClientKey, cost: u32, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > Self::PRUNE_THRESHOLD {
+            let (capacity, refill_per_sec) = (self.capacity, self.refill_per_sec);
+            buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.updated_at);
+                bucket.tokens + elapsed.as_secs_f64() * refill_per_sec < capacity
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = elapsed
+            .as_secs_f64()
+            .mul_add(self.refill_per_sec, bucket.tokens)
+            .min(self.capacity);
+        bucket.updated_at = now;
+
+        let cost = f64::from(cost).min(self.capacity);
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (cost - bucket.tokens) / self.refill_per_sec,
+            ))
+        }
+    }
+}
+
+/// 接続元 `peer` と `X-Forwarded-For` ヘッダーの値から、クライアントのアドレスを決める。
+///
+/// `peer` が信用するプロキシである間だけ、 `X-Forwarded-For` を右から辿る。
+fn client_address(peer: IpAddr, forwarded_for: Option<&str>, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    let forwarded = forwarded_for
+        .into_iter()
+        .flat_map(|value| value.rsplit(','))
+        .map(str::trim);
+
+    for address in forwarded {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match address.parse() {
+            Ok(address) => client = address,
+            Err(_) => break,
+        }
+    }
+
+    client
+}
+
+/// クライアントごとにトークンバケツでリクエストを制限するミドルウェア。
+///
+/// 正しい API キーが付いたリクエストはキーごとに、それ以外はクライアントのアドレスごとに制限する。
+/// 正しくないキーを試すリクエストも制限するため、 [`crate::auth::ApiKeyAuthentication`] より外側で使う。
+/// `limiter` が `None` の場合は何もしない。
+pub struct RateLimit {
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimit {
+    pub const fn new(limiter: Option<Arc<RateLimiter>>) -> Self {
+        Self { limiter }
+    }
+}
+
+pub struct RateLimiter {
+    buckets: TokenBuckets,
+    capacity: u32,
+    trusted_proxies: Vec<IpAddr>,
+    endpoint_costs: HashMap<String, u32>,
+    records_per_token: u32,
+    api_keys: Arc<Vec<ApiKey>>,
+}
+
+impl RateLimiter {
+    /// レート制限が設定されていなければ `None` を返す。
+    pub fn from_config(config: &RateLimitConfig, api_keys: Arc<Vec<ApiKey>>) -> Option<Self> {
+        let capacity = config.capacity?;
+
+        Some(Self {
+            buckets: TokenBuckets::new(capacity, config.refill_per_sec()),
+            capacity,
+            trusted_proxies: config.trusted_proxies.clone(),
+            endpoint_costs: config
+                .endpoint_costs
+                .iter()
+                .map(|endpoint_cost| (endpoint_cost.pattern.clone(), endpoint_cost.cost))
+                .collect(),
+            records_per_token: config.records_per_token(),
+            api_keys,
+        })
+    }
+
+    fn client_key(&self, request: &ServiceRequest) -> Option<ClientKey> {
+        // 正しくないキーは認証で弾かれるが、それまでにかかる分はアドレスごとに数える
+        let api_key = presented_api_key(request)
+            .and_then(|presented| find_api_key(&self.api_keys, presented));
+        if let Some(api_key) = api_key {
+            return Some(ClientKey::ApiKey(api_key.name.clone()));
+        }
+
+        let peer = request.peer_addr()?.ip();
+        let forwarded_for = request
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok());
+
+        Some(ClientKey::Address(client_address(
+            peer,
+            forwarded_for,
+            &self.trusted_proxies,
+        )))
+    }
+
+    /// `/v1` の下のエンドポイントにも、同じエンドポイントのコストを使う
+    fn endpoint_cost(&self, pattern: &str) -> u32 {
+        let pattern = pattern.strip_prefix("/v1").unwrap_or(pattern);
+        self.endpoint_costs.get(pattern).copied().unwrap_or(1)
+    }
+
+    /// エンドポイントごとのコストに、 `limit` で要求された件数に応じた倍率を掛けたもの
+    fn cost(&self, request: &ServiceRequest) -> u32 {
+        let endpoint_cost = request
+            .match_pattern()
+            .map_or(1, |pattern| self.endpoint_cost(&pattern));
+
+        let limit = QString::from(request.query_string())
+            .get("limit")
+            .and_then(|limit| limit.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        endpoint_cost
+            .saturating_mul(limit.div_ceil(self.records_per_token).max(1))
+            .min(self.capacity)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            limiter: self.limiter.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let limited = self
+            .limiter
+            .as_ref()
+            .and_then(|limiter| Some((limiter, limiter.client_key(&request)?)));
+
+        if let Some((limiter, client)) = limited {
+            let cost = limiter.cost(&request);
+
+            if let Err(retry_after) = limiter.buckets.take(client.clone(), cost, Instant::now()) {
+                let retry_after_secs =
+                    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                warn!(
+                    "Rate limited {client:?} on {} {} (cost={cost})",
+                    request.method(),
+                    request.path()
+                );
+
+                let response = HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after_secs.to_string()))
+                    .body(format!(
+                        "too many requests; retry after {retry_after_secs} seconds"
+                    ));
+                return Box::pin(ready(Ok(request
+                    .into_response(response)
+                    .map_into_right_body())));
+            }
+        }
+
+        let response = self.service.call(request);
+        Box::pin(async move { Ok(response.await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::{ApiKeyAuthentication, ApiKeyUsage};
+    use crate::config::{ApiKey, ApiKeyScope, EndpointCost, RateLimitConfig};
+    use crate::rate_limit::{client_address, ClientKey, RateLimit, RateLimiter, TokenBuckets};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn bucket_is_refilled_over_time() {
+        let buckets = TokenBuckets::new(10, 2.0);
+        let client = ClientKey::Address(IpAddr::from([192, 0, 2, 1]));
+        let start = Instant::now();
+
+        assert_eq!(buckets.take(client.clone(), 8, start), Ok(()));
+        assert_eq!(
+            buckets.take(client.clone(), 4, start),
+            Err(Duration::from_secs(1))
+        );
+        assert_eq!(
+            buckets.take(client, 4, start + Duration::from_secs(1)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn forwarded_for_is_honoured_only_from_trusted_proxies() {
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let forwarded_for = Some("198.51.100.7, 192.0.2.1");
+
+        assert_eq!(client_address(proxy, forwarded_for, &[proxy]), client);
+        assert_eq!(client_address(client, forwarded_for, &[proxy]), client);
+        assert_eq!(client_address(proxy, None, &[proxy]), proxy);
+    }
+
+    #[test]
+    fn versioned_endpoints_share_the_cost() {
+        let config = RateLimitConfig {
+            capacity: Some(10),
+            endpoint_costs: vec![EndpointCost {
+                pattern: "/movers".to_string(),
+                cost: 5,
+            }],
+            ..RateLimitConfig::default()
+        };
+        let limiter = RateLimiter::from_config(&config, Arc::default()).unwrap();
+
+        assert_eq!(limiter.endpoint_cost("/movers"), 5);
+        assert_eq!(limiter.endpoint_cost("/v1/movers"), 5);
+        assert_eq!(limiter.endpoint_cost("/v1/ranking"), 1);
+    }
+
+    #[actix_web::test]
+    async fn invalid_api_keys_are_charged_to_the_address() {
+        let keys = Arc::new(vec![ApiKey {
+            name: "partner".to_string(),
+            key: "secret".to_string(),
+            scopes: vec![ApiKeyScope::Export],
+        }]);
+        let config = RateLimitConfig {
+            capacity: Some(1),
+            refill_per_sec: Some(0.001),
+            ..RateLimitConfig::default()
+        };
+        let limiter = RateLimiter::from_config(&config, keys.clone()).map(Arc::new);
+        let app = init_service(
+            App::new()
+                .route("/", web::get().to(HttpResponse::Ok))
+                .wrap(ApiKeyAuthentication::new(
+                    keys.clone(),
+                    Arc::new(ApiKeyUsage::new(&keys)),
+                ))
+                .wrap(RateLimit::new(limiter)),
+        )
+        .await;
+        let peer = SocketAddr::from(([192, 0, 2, 1], 12345));
+        let status_with_key = |key: &'static str| {
+            let request = TestRequest::get()
+                .uri("/")
+                .peer_addr(peer)
+                .insert_header(("X-Api-Key", key))
+                .to_request();
+            let app = &app;
+            async move { call_service(app, request).await.status() }
+        };
+
+        assert_eq!(status_with_key("wrong").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status_with_key("wrong").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // 正しいキーはアドレスとは別に数える
+        assert_eq!(status_with_key("secret").await, StatusCode::OK);
+        assert_eq!(
+            status_with_key("secret").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
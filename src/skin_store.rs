@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// プレーヤーのスキンを手元にキャッシュしたディレクトリから読むストア。
+///
+/// スキンは `{UUID}.png` という名前で置かれている必要がある。
+/// ディレクトリにスキンを置くのは外部 (Mojang の API から定期的に取得するジョブなど) の役目で、このストアは読むだけである。
+pub struct SkinStore {
+    dir: Option<PathBuf>,
+}
+
+impl SkinStore {
+    /// `dir` からスキンを読むストアを作る。 `dir` が `None` の場合、どのプレーヤーのスキンも見つからない。
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+
+    /// `uuid` のプレーヤーのスキンの PNG。置かれていない場合や、置かれているファイルが PNG でない場合は `None` を返す。
+    pub fn skin(&self, uuid: Uuid) -> Result<Option<Vec<u8>>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+
+        let path = dir.join(format!("{uuid}.png"));
+        match fs::read(&path) {
+            Ok(bytes) if bytes.starts_with(PNG_SIGNATURE) => Ok(Some(bytes)),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read skin {}", path.display())),
+        }
+    }
+}
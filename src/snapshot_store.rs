@@ -0,0 +1,554 @@
+use crate::models::{
+    AggregatedPlayerAttribution, AggregationTimeRange, AttributionRecord, Player,
+    PreviousRankedRecord, RankedAttributionRecord, Ranking, RankingSlice,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::Mutex;
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+/// ランキングのスナップショットを、種類・集計期間・取得時刻をキーとして SQLite に追記していくストア。
+///
+/// 保存されたスナップショットは更新されず、保存期間を過ぎたものだけが削除される。
+pub struct RankingSnapshotStore {
+    connection: Mutex<Connection>,
+    minimum_interval: Duration,
+    retention: Option<Duration>,
+}
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS ranking_snapshots (
+        id          INTEGER PRIMARY KEY,
+        attribution TEXT    NOT NULL,
+        time_range  TEXT    NOT NULL,
+        taken_at    INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS ranking_snapshots_key
+        ON ranking_snapshots (attribution, time_range, taken_at);
+
+    CREATE TABLE IF NOT EXISTS ranking_snapshot_records (
+        snapshot_id INTEGER NOT NULL REFERENCES ranking_snapshots (id),
+        rank        INTEGER NOT NULL,
+        uuid        TEXT    NOT NULL,
+        name        TEXT    NOT NULL,
+        last_quit   TEXT    NOT NULL,
+        value       INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS ranking_snapshot_records_snapshot
+        ON ranking_snapshot_records (snapshot_id);
+    CREATE INDEX IF NOT EXISTS ranking_snapshot_records_uuid
+        ON ranking_snapshot_records (uuid, snapshot_id);
+";
+
+/// あるスナップショットにおける、あるプレーヤーの順位と値
+pub struct PlayerSnapshotRecord<Attribution: AggregatedPlayerAttribution> {
+    pub taken_at: DateTime<Utc>,
+    pub rank: u32,
+    pub attribution: Attribution,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumString, Display)]
+pub enum HistoryResolution {
+    #[strum(serialize = "hourly")]
+    Hourly,
+    #[strum(serialize = "daily")]
+    Daily,
+    #[strum(serialize = "weekly")]
+    Weekly,
+}
+
+impl HistoryResolution {
+    /// `time` を含む区間の始まりの時刻。週は月曜日 00:00 (UTC) から始まるものとする。
+    pub fn bucket_start(self, time: DateTime<Utc>) -> DateTime<Utc> {
+        const HOUR: i64 = 60 * 60;
+        const DAY: i64 = 24 * HOUR;
+        // 1970-01-01 は木曜日なので、3日ずらすと月曜日始まりになる
+        const MONDAY_OFFSET: i64 = 3 * DAY;
+
+        let timestamp = time.timestamp();
+        let bucket_start = match self {
+            Self::Hourly => timestamp - timestamp.rem_euclid(HOUR),
+            Self::Daily => timestamp - timestamp.rem_euclid(DAY),
+            Self::Weekly => timestamp - (timestamp + MONDAY_OFFSET).rem_euclid(7 * DAY),
+        };
+
+        Utc.timestamp_opt(bucket_start, 0).unwrap()
+    }
+
+    /// 時刻順に並んだ `records` を区間ごとにまとめ、各区間の最後のレコードをその区間の始まりの時刻と共に返す。
+    pub fn downsample<Attribution: AggregatedPlayerAttribution>(
+        self,
+        records: Vec<PlayerSnapshotRecord<Attribution>>,
+    ) -> Vec<PlayerSnapshotRecord<Attribution>> {
+        let mut downsampled: Vec<PlayerSnapshotRecord<Attribution>> = vec![];
+
+        for record in records {
+            let bucket_start = self.bucket_start(record.taken_at);
+            let record = PlayerSnapshotRecord {
+                taken_at: bucket_start,
+                ..record
+            };
+
+            match downsampled.last_mut() {
+                Some(last) if last.taken_at == bucket_start => *last = record,
+                _ => downsampled.push(record),
+            }
+        }
+
+        downsampled
+    }
+}
+
+impl RankingSnapshotStore {
+    /// `path` にあるデータベースを開く。存在しなければ作成する。
+    ///
+    /// 同じ種類・集計期間のスナップショットは、 `minimum_interval` 以上間隔が空いているときにのみ保存される。
+    /// `retention` が指定されている場合、それより古いスナップショットは新しいスナップショットを保存するときに削除される。
+    pub fn open(
+        path: impl AsRef<Path>,
+        minimum_interval: Duration,
+        retention: Option<Duration>,
+    ) -> Result<Self> {
+        let connection = Connection::open(path).context("failed to open snapshot database")?;
+        Self::with_connection(connection, minimum_interval, retention)
+    }
+
+    pub fn open_in_memory(minimum_interval: Duration, retention: Option<Duration>) -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?, minimum_interval, retention)
+    }
+
+    fn with_connection(
+        connection: Connection,
+        minimum_interval: Duration,
+        retention: Option<Duration>,
+    ) -> Result<Self> {
+        connection
+            .execute_batch(SCHEMA)
+            .context("failed to initialize snapshot database schema")?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            minimum_interval,
+            retention,
+        })
+    }
+
+    /// `ranking` を `taken_at` 時点のスナップショットとして保存し、同じ種類・集計期間の保存期間を過ぎたスナップショットを削除する。
+    ///
+    /// 前回のスナップショットから `minimum_interval` が経過していない場合は何もせず `false` を返す。
+    /// SQLite への書き込みはブロックするので、非同期のタスクからは `spawn_blocking` などを通して呼ぶ必要がある。
+    pub fn persist<Attribution: AggregatedPlayerAttribution>(
+        &self,
+        time_range: AggregationTimeRange,
+        taken_at: DateTime<Utc>,
+        ranking: &Ranking<Attribution>,
+    ) -> Result<bool> {
+        let attribution = Attribution::KIND.to_string();
+        let time_range = time_range.to_string();
+
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        let last_taken_at: Option<i64> = transaction.query_row(
+            "SELECT MAX(taken_at) FROM ranking_snapshots WHERE attribution = ?1 AND time_range = ?2",
+            params![attribution, time_range],
+            |row| row.get(0),
+        )?;
+
+        if let Some(last_taken_at) = last_taken_at {
+            if taken_at.timestamp() - last_taken_at < self.minimum_interval.num_seconds() {
+                return Ok(false);
+            }
+        }
+
+        transaction.execute(
+            "INSERT INTO ranking_snapshots (attribution, time_range, taken_at) VALUES (?1, ?2, ?3)",
+            params![attribution, time_range, taken_at.timestamp()],
+        )?;
+        let snapshot_id = transaction.last_insert_rowid();
+
+        {
+            let mut insert_record = transaction.prepare(
+                "INSERT INTO ranking_snapshot_records (snapshot_id, rank, uuid, name, last_quit, value)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+
+            for ranked_record in ranking.ranked_records() {
+                let record = &ranked_record.attribution_record;
+                insert_record.execute(params![
+                    snapshot_id,
+                    ranked_record.rank,
+                    record.player.uuid.to_string(),
+                    record.player.name,
+                    record.player.last_quit,
+                    i64::try_from(record.attribution.raw_u64_data())?,
+                ])?;
+            }
+        }
+
+        if let Some(retention) = self.retention {
+            let expired_before = (taken_at - retention).timestamp();
+            transaction.execute(
+                "DELETE FROM ranking_snapshot_records WHERE snapshot_id IN (
+                     SELECT id FROM ranking_snapshots
+                     WHERE attribution = ?1 AND time_range = ?2 AND taken_at < ?3
+                 )",
+                params![attribution, time_range, expired_before],
+            )?;
+            transaction.execute(
+                "DELETE FROM ranking_snapshots
+                 WHERE attribution = ?1 AND time_range = ?2 AND taken_at < ?3",
+                params![attribution, time_range, expired_before],
+            )?;
+        }
+
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    /// `at` 時点で最新だったスナップショットから、 `offset` 番目から `limit` 件のレコードを取り出す。
+    /// 各レコードの `previous` には、ひとつ前のスナップショットでの順位と値が入る。
+    /// `at` 以前のスナップショットが存在しない場合は `None` を返す。
+    pub fn paginate_at<Attribution: AggregatedPlayerAttribution>(
+        &self,
+        time_range: AggregationTimeRange,
+        at: DateTime<Utc>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Option<RankingSlice<Attribution>>> {
+        self.select_ranked_records_at(
+            time_range,
+            at,
+            i64::try_from(offset)?,
+            i64::try_from(limit)?,
+        )
+    }
+
+    /// `at` 時点で最新だったスナップショットのすべてのレコードを、 [`Self::paginate_at`] と同じように取り出す。
+    /// `at` 以前のスナップショットが存在しない場合は `None` を返す。
+    pub fn records_at<Attribution: AggregatedPlayerAttribution>(
+        &self,
+        time_range: AggregationTimeRange,
+        at: DateTime<Utc>,
+    ) -> Result<Option<RankingSlice<Attribution>>> {
+        // SQLite では負の LIMIT は上限なしを意味する
+        self.select_ranked_records_at(time_range, at, 0, -1)
+    }
+
+    fn select_ranked_records_at<Attribution: AggregatedPlayerAttribution>(
+        &self,
+        time_range: AggregationTimeRange,
+        at: DateTime<Utc>,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Option<RankingSlice<Attribution>>> {
+        let connection = self.connection.lock().unwrap();
+
+        let snapshot: Option<(i64, i64)> = connection
+            .query_row(
+                "SELECT id, taken_at FROM ranking_snapshots
+                 WHERE attribution = ?1 AND time_range = ?2 AND taken_at <= ?3
+                 ORDER BY taken_at DESC LIMIT 1",
+                params![
+                    Attribution::KIND.to_string(),
+                    time_range.to_string(),
+                    at.timestamp()
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let Some((snapshot_id, taken_at)) = snapshot else {
+            return Ok(None);
+        };
+
+        // 順位と値の変化は、ひとつ前のスナップショットと比べる
+        let previous_snapshot_id: Option<i64> = connection
+            .query_row(
+                "SELECT id FROM ranking_snapshots
+                 WHERE attribution = ?1 AND time_range = ?2 AND taken_at < ?3
+                 ORDER BY taken_at DESC LIMIT 1",
+                params![
+                    Attribution::KIND.to_string(),
+                    time_range.to_string(),
+                    taken_at
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let mut select_records = connection.prepare(
+            "SELECT r.rank, r.uuid, r.name, r.last_quit, r.value, p.rank, p.value
+             FROM ranking_snapshot_records r
+             LEFT JOIN ranking_snapshot_records p ON p.snapshot_id = ?4 AND p.uuid = r.uuid
+             WHERE r.snapshot_id = ?1
+             ORDER BY r.rank, r.rowid
+             LIMIT ?2 OFFSET ?3",
+        )?;
+
+        let records = select_records
+            .query_map(
+                params![snapshot_id, limit, offset, previous_snapshot_id],
+                |row| {
+                    Ok((
+                        row.get::<_, u32>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, DateTime<Utc>>(3)?,
+                        row.get::<_, i64>(4)?,
+                        row.get::<_, Option<u32>>(5)?,
+                        row.get::<_, Option<i64>>(6)?,
+                    ))
+                },
+            )?
+            .map(|row| {
+                let (rank, uuid, name, last_quit, value, previous_rank, previous_value) = row?;
+                let previous = match (previous_rank, previous_value) {
+                    (Some(rank), Some(value)) => Some(PreviousRankedRecord {
+                        rank,
+                        attribution: Attribution::from_raw_u64_data(u64::try_from(value)?),
+                    }),
+                    _ => None,
+                };
+                Ok(RankedAttributionRecord {
+                    rank,
+                    attribution_record: AttributionRecord {
+                        player: Player {
+                            uuid: Uuid::parse_str(&uuid)?,
+                            name,
+                            last_quit,
+                        },
+                        attribution: Attribution::from_raw_u64_data(u64::try_from(value)?),
+                    },
+                    previous,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some(RankingSlice(records)))
+    }
+
+    /// `from` から `to` までに取られたスナップショットにおける、 `uuid` のプレーヤーの順位と値を時刻順に返す。
+    pub fn player_history<Attribution: AggregatedPlayerAttribution>(
+        &self,
+        time_range: AggregationTimeRange,
+        uuid: Uuid,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<PlayerSnapshotRecord<Attribution>>> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut select_records = connection.prepare(
+            "SELECT s.taken_at, r.rank, r.value
+             FROM ranking_snapshot_records r
+             JOIN ranking_snapshots s ON s.id = r.snapshot_id
+             WHERE r.uuid = ?1 AND s.attribution = ?2 AND s.time_range = ?3
+               AND s.taken_at BETWEEN ?4 AND ?5
+             ORDER BY s.taken_at",
+        )?;
+
+        let records = select_records
+            .query_map(
+                params![
+                    uuid.to_string(),
+                    Attribution::KIND.to_string(),
+                    time_range.to_string(),
+                    from.timestamp(),
+                    to.timestamp()
+                ],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, u32>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                },
+            )?
+            .map(|row| {
+                let (taken_at, rank, value) = row?;
+                Ok(PlayerSnapshotRecord {
+                    taken_at: Utc
+                        .timestamp_opt(taken_at, 0)
+                        .single()
+                        .context("snapshot timestamp out of range")?,
+                    rank,
+                    attribution: Attribution::from_raw_u64_data(u64::try_from(value)?),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(records)
+    }
+}
+
+fn parse_time_or_date(specifier: &str, time_of_day: NaiveTime) -> Option<DateTime<Utc>> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(specifier) {
+        return Some(date_time.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(specifier, "%Y-%m-%d").ok()?;
+    Some(Utc.from_utc_datetime(&date.and_time(time_of_day)))
+}
+
+/// `2026-01-01T12:00:00+09:00` のような RFC3339 形式の時刻か、 `2026-01-01` のような日付を解釈する。
+/// 日付のみが与えられた場合は、その日 (UTC) の終わりの時刻とみなす。
+pub fn parse_snapshot_time(specifier: &str) -> Option<DateTime<Utc>> {
+    parse_time_or_date(specifier, NaiveTime::from_hms_opt(23, 59, 59)?)
+}
+
+/// [`parse_snapshot_time`] と同様だが、日付のみが与えられた場合はその日 (UTC) の始まりの時刻とみなす。
+pub fn parse_window_start(specifier: &str) -> Option<DateTime<Utc>> {
+    parse_time_or_date(specifier, NaiveTime::MIN)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::{AggregatedPlayerAttribution, AggregationTimeRange, BreakCount, Ranking};
+    use crate::snapshot_store::{
+        parse_snapshot_time, HistoryResolution, PlayerSnapshotRecord, RankingSnapshotStore,
+    };
+    use crate::test_fixtures::records_of;
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn ranking_of(values: &[u64]) -> Ranking<BreakCount> {
+        let mut ranking = Ranking::default();
+        ranking.hydrate_record_set(records_of(
+            values.iter().map(|&value| (u128::from(value), value)),
+        ));
+        ranking
+    }
+
+    #[test]
+    fn restore_latest_snapshot_before_given_time() {
+        let store = RankingSnapshotStore::open_in_memory(Duration::hours(1), None).unwrap();
+        let first = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let time_range = AggregationTimeRange::All;
+
+        assert!(store
+            .persist(time_range, first, &ranking_of(&[10, 30, 20]))
+            .unwrap());
+        assert!(!store
+            .persist(time_range, first + Duration::minutes(30), &ranking_of(&[1]))
+            .unwrap());
+        assert!(store
+            .persist(time_range, first + Duration::hours(2), &ranking_of(&[5, 5]))
+            .unwrap());
+
+        let restored = store
+            .paginate_at::<BreakCount>(time_range, first + Duration::hours(1), 0, 20)
+            .unwrap()
+            .unwrap();
+        let ranks_and_values = restored
+            .0
+            .iter()
+            .map(|r| (r.rank, r.attribution_record.attribution.raw_u64_data()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranks_and_values, vec![(1, 30), (2, 20), (3, 10)]);
+
+        assert!(store
+            .paginate_at::<BreakCount>(time_range, first - Duration::seconds(1), 0, 20)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn compare_restored_records_with_previous_snapshot() {
+        let store = RankingSnapshotStore::open_in_memory(Duration::hours(1), None).unwrap();
+        let first = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let time_range = AggregationTimeRange::All;
+
+        let mut ranking = Ranking::<BreakCount>::default();
+        ranking.hydrate_record_set(records_of([(1, 10), (2, 20)]));
+        store.persist(time_range, first, &ranking).unwrap();
+        ranking.hydrate_record_set(records_of([(1, 30), (2, 20), (3, 5)]));
+        store
+            .persist(time_range, first + Duration::hours(1), &ranking)
+            .unwrap();
+
+        let previous_of = |at| {
+            store
+                .records_at::<BreakCount>(time_range, at)
+                .unwrap()
+                .unwrap()
+                .0
+                .iter()
+                .map(|r| {
+                    r.previous
+                        .as_ref()
+                        .map(|p| (p.rank, p.attribution.raw_u64_data()))
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(previous_of(first), vec![None, None]);
+        assert_eq!(
+            previous_of(first + Duration::hours(1)),
+            vec![Some((2, 10)), Some((1, 20)), None]
+        );
+    }
+
+    #[test]
+    fn delete_snapshots_older_than_retention() {
+        let store = RankingSnapshotStore::open_in_memory(Duration::zero(), Some(Duration::days(7)))
+            .unwrap();
+        let first = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let time_range = AggregationTimeRange::All;
+
+        for days in [0, 3, 8] {
+            store
+                .persist(time_range, first + Duration::days(days), &ranking_of(&[10]))
+                .unwrap();
+        }
+
+        let snapshot_at = |days| {
+            store
+                .paginate_at::<BreakCount>(time_range, first + Duration::days(days), 0, 20)
+                .unwrap()
+                .is_some()
+        };
+        assert!(!snapshot_at(2));
+        assert!(snapshot_at(3));
+        assert!(snapshot_at(8));
+    }
+
+    #[test]
+    fn downsample_keeps_last_record_of_each_bucket() {
+        let monday = Utc.with_ymd_and_hms(2026, 1, 5, 0, 0, 0).unwrap();
+        let records = [
+            (monday + Duration::hours(1), 3),
+            (monday + Duration::hours(30), 2),
+            (monday + Duration::days(6), 1),
+            (monday + Duration::days(7), 5),
+        ]
+        .into_iter()
+        .map(|(taken_at, rank)| PlayerSnapshotRecord {
+            taken_at,
+            rank,
+            attribution: BreakCount::from_raw_u64_data(0),
+        })
+        .collect::<Vec<_>>();
+
+        let weekly = HistoryResolution::Weekly
+            .downsample(records)
+            .into_iter()
+            .map(|r| (r.taken_at, r.rank))
+            .collect::<Vec<_>>();
+
+        assert_eq!(weekly, vec![(monday, 1), (monday + Duration::days(7), 5)]);
+    }
+
+    #[test]
+    fn parse_date_as_end_of_day() {
+        assert_eq!(
+            parse_snapshot_time("2026-01-01"),
+            Some(Utc.with_ymd_and_hms(2026, 1, 1, 23, 59, 59).unwrap())
+        );
+        assert_eq!(
+            parse_snapshot_time("2026-01-01T09:00:00+09:00"),
+            Some(Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(parse_snapshot_time("yesterday"), None);
+    }
+}
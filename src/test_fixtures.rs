@@ -0,0 +1,73 @@
+//! テストで使うプレーヤーとレコード
+
+use crate::app_models::AllAttributionRecordProviders;
+use crate::models::{
+    AggregatedPlayerAttribution, AggregationTimeRange, AttributionRecord,
+    AttributionRecordProvider, Player,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use uuid::Uuid;
+
+/// UUID が `Uuid::from_u128(id)` で、名前が `player{id}` のプレーヤー
+pub fn player(id: u128) -> Player {
+    Player {
+        uuid: Uuid::from_u128(id),
+        name: format!("player{id}"),
+        last_quit: Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap(),
+    }
+}
+
+/// [`player`] で作ったプレーヤーが `value` を持つレコード
+pub fn record<Attribution: AggregatedPlayerAttribution>(
+    id: u128,
+    value: u64,
+) -> AttributionRecord<Attribution> {
+    AttributionRecord {
+        player: player(id),
+        attribution: Attribution::from_raw_u64_data(value),
+    }
+}
+
+/// `(id, value)` の組ごとの [`record`]
+pub fn records_of<Attribution: AggregatedPlayerAttribution>(
+    values: impl IntoIterator<Item = (u128, u64)>,
+) -> Vec<AttributionRecord<Attribution>> {
+    values
+        .into_iter()
+        .map(|(id, value)| record(id, value))
+        .collect()
+}
+
+/// `values` の先頭から順に、 `id` が 1, 2, ... のプレーヤーが値を持つレコード
+pub fn records_with_values<Attribution: AggregatedPlayerAttribution>(
+    values: &[u64],
+) -> Vec<AttributionRecord<Attribution>> {
+    records_of((1..).zip(values.iter().copied()))
+}
+
+/// どの集計期間についても、 `id` が 1 のプレーヤーが 42 を持つレコードだけを返す
+pub struct SinglePlayerProvider;
+
+#[async_trait]
+impl<Attribution: AggregatedPlayerAttribution + Send + 'static>
+    AttributionRecordProvider<Attribution> for SinglePlayerProvider
+{
+    async fn get_all_attribution_records(
+        &self,
+        _time_range: AggregationTimeRange,
+    ) -> Result<Vec<AttributionRecord<Attribution>>> {
+        Ok(vec![record(1, 42)])
+    }
+}
+
+/// すべての種類で [`SinglePlayerProvider`] を使う
+pub fn single_player_providers() -> AllAttributionRecordProviders {
+    AllAttributionRecordProviders {
+        break_count_provider: Box::new(SinglePlayerProvider),
+        build_count_provider: Box::new(SinglePlayerProvider),
+        play_ticks_provider: Box::new(SinglePlayerProvider),
+        vote_count_provider: Box::new(SinglePlayerProvider),
+    }
+}
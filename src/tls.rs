@@ -0,0 +1,117 @@
+use crate::config::{TlsConfig, TlsVersion};
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// ファイルから読み込んだ証明書を返し、要求に応じて読み込み直せる [`ResolvesServerCert`]
+pub struct ReloadableCertificateResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .with_context(|| format!("failed to read certificates from {}", cert_path.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    if certificates.is_empty() {
+        bail!("no certificate found in {}", cert_path.display());
+    }
+
+    let mut key_reader = BufReader::new(File::open(key_path)?);
+    let private_key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)
+            .with_context(|| format!("failed to read private key from {}", key_path.display()))?
+        {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break PrivateKey(key),
+            Some(_) => continue,
+            None => bail!("no private key found in {}", key_path.display()),
+        }
+    };
+
+    let signing_key = rustls::sign::any_supported_type(&private_key)
+        .map_err(|_| anyhow!("unsupported private key in {}", key_path.display()))?;
+
+    Ok(CertifiedKey::new(certificates, signing_key))
+}
+
+impl ReloadableCertificateResolver {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            certified_key: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+        })
+    }
+
+    /// 証明書と秘密鍵をファイルから読み込み直す。読み込みに失敗した場合は、それまでの証明書を使い続ける。
+    pub fn reload(&self) -> Result<()> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.certified_key.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+/// `tls_config` に従って `rustls` のサーバー設定を作る。証明書は `resolver` から取り出される。
+pub fn server_config(
+    tls_config: &TlsConfig,
+    resolver: Arc<ReloadableCertificateResolver>,
+) -> Result<ServerConfig> {
+    let versions = tls_config
+        .versions
+        .iter()
+        .map(|version| match version {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&versions)?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver))
+}
+
+/// SIGHUP を受け取るたびに `resolver` の証明書を読み込み直し続ける。
+#[cfg(unix)]
+pub async fn reload_on_sighup(resolver: Arc<ReloadableCertificateResolver>) -> Result<()> {
+    use log::{error, info};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match resolver.reload() {
+            Ok(()) => info!("Reloaded TLS certificate"),
+            Err(e) => error!("Error reloading TLS certificate, keeping the previous one: {e:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// SIGHUP のない環境では証明書を読み込み直せないので、何もしない。証明書を更新するにはサーバーを再起動する必要がある。
+#[cfg(not(unix))]
+#[allow(clippy::unused_async)]
+pub async fn reload_on_sighup(_resolver: Arc<ReloadableCertificateResolver>) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,502 @@
+use crate::app_models::{shutdown_requested, AppState, PrivacyList, RankingUpdate};
+use crate::config::{Milestone, WebhookConfig};
+use crate::handlers::presentation_models::{
+    ranked_record_to_presentation_player_ranking_record, PlayerRankingRecord,
+};
+use crate::models::{AggregatedPlayerAttribution, AggregationTimeRange, AttributionKind, Ranking};
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::Serialize;
+use sha2::Sha256;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch};
+use uuid::Uuid;
+
+/// 通知ごとに割り当てられる ID を入れるヘッダー。再試行しても変わらないので、受け取る側で重複を取り除くのに使える。
+pub const WEBHOOK_ID_HEADER: &str = "x-webhook-id";
+
+/// 通知の本文の HMAC-SHA256 署名を `sha256=<16進数>` の形式で入れるヘッダー
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// 通知するできごと
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum WebhookEvent {
+    /// 1位が入れ替わった
+    NewLeader { record: PlayerRankingRecord },
+    /// プレーヤーが上位 `top_n` 位以内に新しく入った
+    EnteredTop {
+        top_n: u32,
+        record: PlayerRankingRecord,
+    },
+    /// プレーヤーの値が節目に達した
+    Milestone {
+        threshold: u64,
+        record: PlayerRankingRecord,
+    },
+}
+
+/// 一度のランキングの更新で起きたできごとをまとめた、通知の本文
+#[derive(Serialize)]
+struct WebhookPayload {
+    id: Uuid,
+    #[serde(rename = "type")]
+    kind: String,
+    time_range: String,
+    occurred_at: DateTime<Utc>,
+    events: Vec<WebhookEvent>,
+}
+
+/// どのできごとを通知するか
+struct EventRules {
+    new_leader: bool,
+    top_n: Option<u32>,
+    milestones: Vec<Milestone>,
+}
+
+/// 更新されたばかりの `ranking` から、前回の更新からの間に起きたできごとを見つける。
+fn detect_events<Attribution: AggregatedPlayerAttribution>(
+    ranking: &Ranking<Attribution>,
+    time_range: AggregationTimeRange,
+    rules: &EventRules,
+    privacy_list: &PrivacyList,
+) -> Vec<WebhookEvent> {
+    let records = ranking.ranked_records();
+
+    // 起動直後などで前回のランキングが空だった場合、すべてのプレーヤーが新しく入ってきたように見えてしまう
+    if !records.iter().any(|record| record.previous.is_some()) {
+        return vec![];
+    }
+
+    let thresholds = rules
+        .milestones
+        .iter()
+        .filter(|milestone| {
+            milestone.kind == Attribution::KIND && time_range == AggregationTimeRange::All
+        })
+        .map(|milestone| milestone.value)
+        .collect::<Vec<_>>();
+
+    let mut events = vec![];
+    for record in records {
+        let previous_rank = record.previous.as_ref().map(|previous| previous.rank);
+
+        if rules.new_leader && record.rank == 1 && previous_rank != Some(1) {
+            events.push(WebhookEvent::NewLeader {
+                record: ranked_record_to_presentation_player_ranking_record(record, privacy_list),
+            });
+        }
+
+        if let Some(top_n) = rules.top_n {
+            if record.rank <= top_n && previous_rank.is_none_or(|rank| rank > top_n) {
+                events.push(WebhookEvent::EnteredTop {
+                    top_n,
+                    record: ranked_record_to_presentation_player_ranking_record(
+                        record,
+                        privacy_list,
+                    ),
+                });
+            }
+        }
+
+        // 前回の値がわからないプレーヤーについては、節目を越えたかどうか判断できない
+        if let Some(previous) = &record.previous {
+            let value = record.attribution_record.attribution.raw_u64_data();
+            let previous_value = previous.attribution.raw_u64_data();
+            for &threshold in &thresholds {
+                if previous_value < threshold && threshold <= value {
+                    events.push(WebhookEvent::Milestone {
+                        threshold,
+                        record: ranked_record_to_presentation_player_ranking_record(
+                            record,
+                            privacy_list,
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    events
+}
+
+/// `update` で更新されたランキングで起きたできごとを、通知の本文にする。何も起きていなければ `None` を返す。
+async fn build_payload(
+    state: &AppState,
+    update: RankingUpdate,
+    rules: &EventRules,
+) -> Option<WebhookPayload> {
+    macro_rules! detect_using {
+        ($ranking:expr) => {
+            detect_events(
+                &*$ranking.for_time_range(update.time_range).read().await,
+                update.time_range,
+                rules,
+                &state.privacy_list,
+            )
+        };
+    }
+
+    let events = match update.kind {
+        AttributionKind::Break => detect_using!(state.break_count_rankings),
+        AttributionKind::Build => detect_using!(state.build_count_rankings),
+        AttributionKind::PlayTicks => detect_using!(state.play_ticks_rankings),
+        AttributionKind::VoteCount => detect_using!(state.vote_count_rankings),
+    };
+
+    (!events.is_empty()).then(|| WebhookPayload {
+        id: Uuid::new_v4(),
+        kind: update.kind.to_string(),
+        time_range: update.time_range.to_string(),
+        occurred_at: Utc::now(),
+        events,
+    })
+}
+
+/// `body` の HMAC-SHA256 署名を16進数で返す。
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// 送れなかった通知として書き出す内容
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    failed_at: DateTime<Utc>,
+    url: &'a str,
+    id: Uuid,
+    attempts: u32,
+    error: &'a str,
+    payload: serde_json::Value,
+}
+
+struct WebhookSender {
+    client: awc::Client,
+    secret: Vec<u8>,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    dead_letter_file: Option<PathBuf>,
+}
+
+impl WebhookSender {
+    fn from_config(config: &WebhookConfig) -> Self {
+        Self {
+            client: awc::Client::builder().timeout(config.timeout()).finish(),
+            secret: config
+                .secret
+                .as_ref()
+                .map(|secret| secret.0.clone().into_bytes())
+                .unwrap_or_default(),
+            max_attempts: config.max_attempts(),
+            initial_backoff: config.initial_backoff(),
+            dead_letter_file: config.dead_letter_file.as_ref().map(PathBuf::from),
+        }
+    }
+
+    async fn send(&self, url: &str, id: Uuid, body: &Bytes) -> Result<(), String> {
+        let response = self
+            .client
+            .post(url)
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .insert_header((WEBHOOK_ID_HEADER, id.to_string()))
+            .insert_header((
+                WEBHOOK_SIGNATURE_HEADER,
+                format!("sha256={}", sign(&self.secret, body)),
+            ))
+            .send_body(body.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("receiver responded with {}", response.status()))
+        }
+    }
+
+    /// `url` に通知を送る。失敗した場合は間隔を倍にしながら再試行し、それでも送れなければ dead letter として書き出す。
+    ///
+    /// 終了を求められた場合は、それ以上再試行せずに dead letter として書き出す。
+    async fn deliver(&self, url: &str, id: Uuid, body: Bytes, mut shutdown: watch::Receiver<bool>) {
+        let mut backoff = self.initial_backoff;
+        let mut attempts = 0;
+
+        let error = loop {
+            attempts += 1;
+            let result = tokio::select! {
+                result = self.send(url, id, &body) => result,
+                () = shutdown_requested(&mut shutdown) => break "shutting down".to_string(),
+            };
+
+            let error = match result {
+                Ok(()) => {
+                    info!("Delivered webhook {id} to {url}");
+                    return;
+                }
+                Err(error) => error,
+            };
+            if attempts >= self.max_attempts {
+                break error;
+            }
+
+            warn!("Error delivering webhook {id} to {url} (attempt {attempts}): {error}; retrying in {backoff:?}");
+            tokio::select! {
+                () = tokio::time::sleep(backoff) => {}
+                () = shutdown_requested(&mut shutdown) => break error,
+            }
+            backoff = backoff.saturating_mul(2);
+        };
+
+        self.dead_letter(url, id, &body, attempts, &error);
+    }
+
+    fn dead_letter(&self, url: &str, id: Uuid, body: &Bytes, attempts: u32, error: &str) {
+        error!("Gave up delivering webhook {id} to {url} after {attempts} attempts: {error}");
+
+        let Some(path) = &self.dead_letter_file else {
+            return;
+        };
+        let line = serde_json::json!(DeadLetter {
+            failed_at: Utc::now(),
+            url,
+            id,
+            attempts,
+            error,
+            payload: serde_json::from_slice(body).unwrap_or_default(),
+        });
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{line}"));
+        if let Err(e) = written {
+            error!(
+                "Error writing webhook {id} to dead letter file {}: {e}; payload: {}",
+                path.display(),
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+}
+
+/// ランキングが更新されるたびに、設定されたできごとが起きていれば `config` の URL に通知を送る。
+///
+/// `updates` は、ランキングの更新が始まる前に [`AppState::subscribe_ranking_updates`] で受け取っておく必要がある。
+/// 終了を求められると、送っている途中の通知を dead letter として書き出してから終わる。
+pub async fn webhook_process(
+    state: &AppState,
+    config: &WebhookConfig,
+    mut updates: broadcast::Receiver<RankingUpdate>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let sender = WebhookSender::from_config(config);
+    let rules = EventRules {
+        new_leader: config.new_leader,
+        top_n: config.top_n,
+        milestones: config.milestones.clone(),
+    };
+    let mut deliveries = FuturesUnordered::new();
+
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    let Some(payload) = build_payload(state, update, &rules).await else {
+                        continue;
+                    };
+                    let body = Bytes::from(serde_json::json!(payload).to_string());
+                    for url in &config.urls {
+                        deliveries.push(sender.deliver(url, payload.id, body.clone(), shutdown.clone()));
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Skipped webhooks for {skipped} ranking updates that could not be processed in time");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            Some(()) = deliveries.next() => {}
+            () = shutdown_requested(&mut shutdown) => break,
+        }
+    }
+
+    while deliveries.next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app_models::PrivacyList;
+    use crate::config::{Milestone, WebhookConfig, WebhookSecret};
+    use crate::models::{AggregationTimeRange, AttributionKind, BreakCount, Ranking};
+    use crate::test_fixtures::records_with_values;
+    use crate::webhooks::{
+        detect_events, sign, EventRules, WebhookSender, WEBHOOK_SIGNATURE_HEADER,
+    };
+    use actix_web::web::{Bytes, Data};
+    use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::sync::watch;
+    use uuid::Uuid;
+
+    #[test]
+    fn detect_new_leader_top_n_entries_and_milestones() {
+        let rules = EventRules {
+            new_leader: true,
+            top_n: Some(2),
+            milestones: vec![Milestone {
+                kind: AttributionKind::Break,
+                value: 1000,
+            }],
+        };
+        let mut ranking = Ranking::default();
+        let events = |ranking: &Ranking<BreakCount>| {
+            serde_json::json!(detect_events(
+                ranking,
+                AggregationTimeRange::All,
+                &rules,
+                &PrivacyList::default()
+            ))
+        };
+
+        ranking.hydrate_record_set(records_with_values(&[900, 800, 700]));
+        assert_eq!(events(&ranking), serde_json::json!([]));
+
+        // player3 が 700 から 1100 に伸びて1位になる
+        ranking.hydrate_record_set(records_with_values(&[900, 800, 1100]));
+        let events = events(&ranking);
+        let summary = events
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                (
+                    event["event"].as_str().unwrap(),
+                    event["record"]["player"]["name"].as_str().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("new_leader", "player3"),
+                ("entered_top", "player3"),
+                ("milestone", "player3"),
+            ]
+        );
+    }
+
+    #[derive(Default)]
+    struct Received {
+        requests: Mutex<Vec<(String, String, Bytes)>>,
+    }
+
+    /// `/flaky` には最初の一回だけ、 `/broken` には常に `500` を返す受信側
+    async fn receive(request: HttpRequest, body: Bytes, received: Data<Received>) -> HttpResponse {
+        let signature = request
+            .headers()
+            .get(WEBHOOK_SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let mut requests = received.requests.lock().unwrap();
+        requests.push((request.path().to_string(), signature, body));
+
+        let flaky_requests = requests
+            .iter()
+            .filter(|(path, ..)| path == "/flaky")
+            .count();
+        if request.path() == "/flaky" && flaky_requests > 1 {
+            HttpResponse::NoContent().finish()
+        } else {
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    #[actix_web::test]
+    async fn retry_failed_deliveries_and_write_dead_letters() {
+        let received = Data::new(Received::default());
+        let app_received = received.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_received.clone())
+                .default_service(actix_web::web::to(receive))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let dead_letter_file =
+            std::env::temp_dir().join(format!("webhook-dead-letters-{}.jsonl", std::process::id()));
+        let sender = WebhookSender::from_config(&WebhookConfig {
+            secret: Some(WebhookSecret("secret".to_string())),
+            max_attempts: Some(3),
+            dead_letter_file: Some(dead_letter_file.to_string_lossy().into_owned()),
+            ..WebhookConfig::default()
+        });
+        let sender = WebhookSender {
+            initial_backoff: Duration::from_millis(10),
+            ..sender
+        };
+        let (_shutdown_sender, shutdown) = watch::channel(false);
+        let body = Bytes::from_static(br#"{"events":[]}"#);
+
+        for path in ["/flaky", "/broken"] {
+            sender
+                .deliver(
+                    &format!("http://{address}{path}"),
+                    Uuid::from_u128(1),
+                    body.clone(),
+                    shutdown.clone(),
+                )
+                .await;
+        }
+        server_handle.stop(false).await;
+
+        let requests = received.requests.lock().unwrap();
+        let count = |expected: &str| {
+            requests
+                .iter()
+                .filter(|(path, ..)| path == expected)
+                .count()
+        };
+        assert_eq!((count("/flaky"), count("/broken")), (2, 3));
+        let expected_signature = format!("sha256={}", sign(b"secret", &body));
+        assert!(requests
+            .iter()
+            .all(
+                |(_, signature, received_body)| *signature == expected_signature
+                    && *received_body == body
+            ));
+
+        let dead_letters = std::fs::read_to_string(&dead_letter_file).unwrap();
+        std::fs::remove_file(&dead_letter_file).unwrap();
+        let dead_letters = dead_letters
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0]["url"], format!("http://{address}/broken"));
+        assert_eq!(dead_letters[0]["attempts"], 3);
+        assert_eq!(
+            dead_letters[0]["payload"],
+            serde_json::json!({ "events": [] })
+        );
+    }
+}